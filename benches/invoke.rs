@@ -0,0 +1,128 @@
+//! Benchmarks of `SessionMiddleware::invoke`, with and without a valid
+//! token.
+//!
+//! The requests are parsed from memory and passed to the middleware
+//! directly, so the numbers are not drowned in socket and thread
+//! overhead.  Parsing the request and creating the response is
+//! included, but is the same for all benchmarks.
+#[macro_use]
+extern crate criterion;
+extern crate hyper;
+extern crate nickel;
+extern crate nickel_jwt_session;
+
+use criterion::Criterion;
+use hyper::buffer::BufReader;
+use hyper::header::Headers;
+use hyper::net::NetworkStream;
+use nickel::{Middleware, ReloadPolicy, Request, Response, TemplateCache};
+use nickel_jwt_session::session_core::TokenOptions;
+use nickel_jwt_session::*;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const KEY: &'static str = "A benchmark key, at least 32 bytes long";
+
+/// A stream that reads a request from memory and discards writes.
+struct MemoryStream {
+    input: io::Cursor<Vec<u8>>,
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl NetworkStream for MemoryStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(([127, 0, 0, 1], 4711).into())
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Pass a request through the middleware, and check if the request
+/// got an authorized user.
+fn invoke_once(
+    middleware: &SessionMiddleware,
+    templates: &TemplateCache,
+    request: &[u8],
+) -> bool {
+    let mut stream = MemoryStream {
+        input: io::Cursor::new(request.to_owned()),
+    };
+    let addr = stream.peer_addr().expect("peer addr");
+    let stream: &mut dyn NetworkStream = &mut stream;
+    let mut reader = BufReader::new(stream);
+    let origin = hyper::server::Request::new(&mut reader, addr)
+        .expect("request");
+    let mut req = Request::from_internal(origin, &());
+    let (mut output, mut headers) = (io::sink(), Headers::new());
+    let res = Response::from_internal(
+        hyper::server::Response::new(&mut output, &mut headers),
+        templates,
+        &(),
+    );
+    let result = middleware.invoke(&mut req, res);
+    assert!(result.is_ok());
+    req.authorized_user().is_some()
+}
+
+fn request(cookie: Option<&str>) -> Vec<u8> {
+    let mut request = "GET / HTTP/1.1\r\nHost: localhost\r\n".to_owned();
+    if let Some(cookie) = cookie {
+        request.push_str(&format!("Cookie: jwt={}\r\n", cookie));
+    }
+    request.push_str("\r\n");
+    request.into_bytes()
+}
+
+fn invoke(c: &mut Criterion) {
+    let templates = TemplateCache::with_policy(ReloadPolicy::Never);
+    for &(name, cache) in &[("invoke", None), ("cached", Some(1000))] {
+        let mut middleware = SessionMiddleware::new(KEY);
+        if let Some(capacity) = cache {
+            middleware = middleware.cache_verified_tokens(capacity);
+        }
+        let token = middleware
+            .core()
+            .make_token(TokenOptions {
+                subject: Some("carl".to_owned()),
+                ..Default::default()
+            })
+            .expect("token");
+
+        let without = request(None);
+        assert!(!invoke_once(&middleware, &templates, &without));
+        c.bench_function(&format!("{} without token", name), |b| {
+            b.iter(|| invoke_once(&middleware, &templates, &without))
+        });
+
+        let with = request(Some(&token));
+        assert!(invoke_once(&middleware, &templates, &with));
+        c.bench_function(&format!("{} with token", name), |b| {
+            b.iter(|| invoke_once(&middleware, &templates, &with))
+        });
+    }
+}
+
+criterion_group!(benches, invoke);
+criterion_main!(benches);
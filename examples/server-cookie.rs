@@ -16,11 +16,19 @@ use time::Duration;
 fn main() {
     env_logger::init().unwrap();
     let mut server = Nickel::new();
-    server.utilize(
-        SessionMiddleware::new("My very secret key").expiration_time(
-            Duration::minutes(1), // Short, to see it expire.
-        ),
-    );
+    // Run with e.g. NICKEL_JWT_KEY_FILE=/path/to/key, or any other
+    // NICKEL_JWT_* settings.  Without a key in the environment, a
+    // fixed example key is used.
+    let session = match SessionMiddleware::from_env() {
+        Ok(session) => session,
+        Err(ConfigError::Missing(_)) => {
+            SessionMiddleware::new("My very secret key, for examples only")
+        }
+        Err(err) => panic!("Session configuration: {}", err),
+    };
+    server.utilize(session.expiration_time(
+        Duration::minutes(1), // Short, to see it expire.
+    ));
 
     server.get("/", public);
     server.get("/login", login);
@@ -43,16 +51,20 @@ fn login<'mw>(
     // A real login view would get a username/password pair or a CAS
     // ticket or something, but in this example, we just consider
     // "carl" logged in.
-    res.set_jwt_user("carl");
-    res.redirect("/")
+    match res.set_jwt_user("carl") {
+        Ok(()) => res.redirect("/"),
+        Err(err) => res.error(StatusCode::InternalServerError, err.to_string()),
+    }
 }
 
 fn logout<'mw>(
     _req: &mut Request,
     mut res: Response<'mw>,
 ) -> MiddlewareResult<'mw> {
-    res.clear_jwt();
-    res.redirect("/")
+    match res.clear_jwt() {
+        Ok(()) => res.redirect("/"),
+        Err(err) => res.error(StatusCode::InternalServerError, err.to_string()),
+    }
 }
 
 fn private<'mw>(
@@ -19,7 +19,7 @@ fn main() {
     env_logger::init().unwrap();
     let mut server = Nickel::new();
     server.utilize(
-        SessionMiddleware::new("My very secret key")
+        SessionMiddleware::new("My very secret key, at least 32 bytes")
             .expiration_time(Duration::minutes(1)) // Short, to see it expire.
             .using(TokenLocation::AuthorizationHeader),
     );
@@ -48,16 +48,20 @@ fn login<'mw>(
     let mut d = BTreeMap::new();
     d.insert("full_name".to_owned(), "Carl Smith".to_json());
     d.insert("admin".to_owned(), true.to_json());
-    res.set_jwt_user_and_custom_claims("carl", d);
-    res.redirect("/")
+    match res.set_jwt_user_and_custom_claims("carl", d) {
+        Ok(()) => res.redirect("/"),
+        Err(err) => res.error(StatusCode::InternalServerError, err.to_string()),
+    }
 }
 
 fn logout<'mw>(
     _req: &mut Request,
     mut res: Response<'mw>,
 ) -> MiddlewareResult<'mw> {
-    res.clear_jwt();
-    res.redirect("/")
+    match res.clear_jwt() {
+        Ok(()) => res.redirect("/"),
+        Err(err) => res.error(StatusCode::InternalServerError, err.to_string()),
+    }
 }
 
 fn private<'mw>(
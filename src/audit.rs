@@ -0,0 +1,127 @@
+//! Audit events for logins, logouts and rejected tokens.
+
+use rustc_serialize::json::Json;
+use session_core::Rejection;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// What happened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditKind {
+    /// A token was set on a response.
+    Login,
+    /// A token was cleared on a response.
+    Logout,
+    /// The token of a request was rejected, for the given reason.
+    Rejected(Rejection),
+}
+
+/// An event for the audit trail.
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    /// What happened.
+    pub kind: AuditKind,
+    /// When it happened, in seconds since the epoch.
+    pub time: u64,
+    /// The sub (subject) of the token, if known.
+    pub subject: Option<String>,
+    /// The jti (jwt id) of the token, if known.
+    pub jti: Option<String>,
+    /// The remote address of the request.
+    pub remote_addr: String,
+    /// The path of the request.
+    ///
+    /// The query is left out, as it may contain a token.
+    pub uri: String,
+}
+
+impl AuditEvent {
+    /// Get the event as a json object.
+    ///
+    /// The kind is in the `event` field, and the reason for a
+    /// rejection in the `reason` field.
+    pub fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        let event = match self.kind {
+            AuditKind::Login => "login",
+            AuditKind::Logout => "logout",
+            AuditKind::Rejected(reason) => {
+                let reason = Json::String(reason.to_string());
+                obj.insert("reason".to_owned(), reason);
+                "rejected"
+            }
+        };
+        obj.insert("event".to_owned(), Json::String(event.to_owned()));
+        obj.insert("time".to_owned(), Json::U64(self.time));
+        if let Some(ref subject) = self.subject {
+            obj.insert("sub".to_owned(), Json::String(subject.clone()));
+        }
+        if let Some(ref jti) = self.jti {
+            obj.insert("jti".to_owned(), Json::String(jti.clone()));
+        }
+        obj.insert(
+            "remote_addr".to_owned(),
+            Json::String(self.remote_addr.clone()),
+        );
+        obj.insert("uri".to_owned(), Json::String(self.uri.clone()));
+        Json::Object(obj)
+    }
+}
+
+/// A receiver of audit events.
+///
+/// The sink is called synchronously while handling the request, so
+/// it should be fast, and it must handle its own errors.
+pub trait AuditSink: Send + Sync {
+    /// Record an event.
+    fn record(&self, event: &AuditEvent);
+}
+
+/// An `AuditSink` that appends each event as a line of json to a file.
+pub struct JsonLinesSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesSink {
+    /// Open a file for appending events, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<JsonLinesSink, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLinesSink { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record(&self, event: &AuditEvent) {
+        let line = format!("{}\n", event.to_json());
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            error!("Failed to write audit event {:?}: {}", event.kind, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejection_as_json() {
+        let event = AuditEvent {
+            kind: AuditKind::Rejected(Rejection::Expired),
+            time: 1500000000,
+            subject: None,
+            jti: None,
+            remote_addr: "127.0.0.1:4711".to_owned(),
+            uri: "/private".to_owned(),
+        };
+        assert_eq!(
+            event.to_json().to_string(),
+            r#"{"event":"rejected","reason":"token expired","#.to_owned() +
+                r#""remote_addr":"127.0.0.1:4711","time":1500000000,"# +
+                r#""uri":"/private"}"#
+        );
+    }
+}
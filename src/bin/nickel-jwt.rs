@@ -0,0 +1,203 @@
+//! Mint, decode and verify session tokens offline.
+//!
+//! The configuration is read from the same `NICKEL_JWT_*` environment
+//! variables as `SessionMiddleware::from_env()` rather than from the
+//! command line, and tokens can be read from stdin, so neither the
+//! key nor tokens end up in the shell history or the process list.
+extern crate nickel_jwt_session;
+extern crate rustc_serialize;
+extern crate time;
+
+use nickel_jwt_session::session_core::TokenOptions;
+use nickel_jwt_session::SessionCore;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+use std::env;
+use std::io::{self, Read};
+use std::process::exit;
+use time::Duration;
+
+const USAGE: &'static str = "\
+Usage:
+  nickel-jwt mint [options] USER
+  nickel-jwt decode [TOKEN]
+  nickel-jwt verify [options] [TOKEN]
+
+If TOKEN is omitted or is -, the token is read from stdin.
+
+Options:
+  --issuer ISS        The iss (issuer) claim of a minted token, or the
+                      one a verified token must have
+  --expires SECONDS   Lifetime of a minted token
+  --audience AUD      The aud (audience) claim of a minted token, or
+                      the one a verified token must have
+  --claim NAME=JSON   Add a custom claim to a minted token
+
+The server key and other configuration is read from the NICKEL_JWT_KEY
+or NICKEL_JWT_KEY_FILE and other NICKEL_JWT_* environment variables.
+";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+    let args = args.collect::<Vec<_>>();
+    let result = match command.as_ref() {
+        "mint" => mint(&args),
+        "decode" => decode(&args),
+        "verify" => verify(&args),
+        _ => Err(USAGE.to_owned()),
+    };
+    if let Err(msg) = result {
+        eprintln!("{}", msg.trim_end());
+        exit(1);
+    }
+}
+
+/// Parsed command line options and remaining arguments.
+struct Args {
+    core: Option<SessionCore>,
+    issuer: Option<String>,
+    options: TokenOptions,
+    free: Vec<String>,
+}
+
+fn parse_args(args: &[String], need_key: bool) -> Result<Args, String> {
+    let mut core = if need_key {
+        Some(
+            SessionCore::from_env("NICKEL_JWT_")
+                .map_err(|e| e.to_string())?,
+        )
+    } else {
+        None
+    };
+    let mut issuer = None;
+    let mut options = TokenOptions::default();
+    let mut free = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_ref() {
+            "--issuer" => {
+                let iss = value()?;
+                core = core.map(|core| core.issuer(&iss));
+                issuer = Some(iss);
+            }
+            "--expires" => {
+                let seconds = value()?
+                    .parse::<u32>()
+                    .map_err(|e| format!("Bad --expires: {}", e))?;
+                if seconds == 0 {
+                    return Err("Bad --expires: must be positive".into());
+                }
+                options.expiration = Some(Duration::seconds(seconds.into()));
+            }
+            "--audience" => options.audience = Some(value()?),
+            "--claim" => {
+                let claim = value()?;
+                let mut parts = claim.splitn(2, '=');
+                let name = parts.next().unwrap_or("").to_owned();
+                let json = parts.next().ok_or_else(|| {
+                    format!("Bad --claim {:?}, expected NAME=JSON", claim)
+                })?;
+                let json = Json::from_str(json)
+                    .map_err(|e| format!("Bad json for {}: {}", name, e))?;
+                options.claims.insert(name, json);
+            }
+            "-" => free.push(arg.clone()),
+            _ if arg.starts_with('-') => {
+                return Err(format!("Unknown option {}\n\n{}", arg, USAGE));
+            }
+            _ => free.push(arg.clone()),
+        }
+    }
+    Ok(Args {
+        core: core,
+        issuer: issuer,
+        options: options,
+        free: free,
+    })
+}
+
+/// Get the token argument, or read it from stdin.
+fn token_arg(free: &[String]) -> Result<String, String> {
+    match free.first().map(|t| t.as_ref()) {
+        Some("-") | None => {
+            let mut token = String::new();
+            io::stdin()
+                .read_to_string(&mut token)
+                .map_err(|e| format!("Failed to read token: {}", e))?;
+            Ok(token.trim().to_owned())
+        }
+        Some(token) => Ok(token.to_owned()),
+    }
+}
+
+fn mint(args: &[String]) -> Result<(), String> {
+    let Args {
+        core,
+        mut options,
+        free,
+        ..
+    } = parse_args(args, true)?;
+    let core = core.unwrap();
+    match free.first() {
+        Some(user) => options.subject = Some(user.clone()),
+        None => return Err(USAGE.to_owned()),
+    }
+    let token = core.make_token(options).map_err(|e| e.to_string())?;
+    println!("{}", token);
+    Ok(())
+}
+
+fn decode(args: &[String]) -> Result<(), String> {
+    let token = token_arg(&parse_args(args, false)?.free)?;
+    let encrypted = token.split('.').count() == 5;
+    let titles: &[&str] = if encrypted {
+        &["Header"]
+    } else {
+        &["Header", "Claims"]
+    };
+    let mut parts = token.split('.');
+    for title in titles {
+        let part = parts.next().unwrap_or("");
+        let json = part
+            .from_base64()
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .and_then(|text| Json::from_str(&text).ok())
+            .ok_or_else(|| format!("Malformed token: bad {}", title))?;
+        println!("{}:\n{}", title, json.pretty());
+    }
+    if encrypted {
+        println!("Claims are encrypted, and can only be read with the key.");
+    }
+    Ok(())
+}
+
+fn verify(args: &[String]) -> Result<(), String> {
+    let args = parse_args(args, true)?;
+    let core = args.core.unwrap();
+    let token = token_arg(&args.free)?;
+    let context = core
+        .verify(&token)
+        .map_err(|rejection| format!("Invalid token: {}", rejection))?;
+    let reg = &context.registered;
+    if args.issuer.is_some() && reg.iss != args.issuer {
+        return Err("Invalid token: wrong issuer".to_owned());
+    }
+    if args.options.audience.is_some() && reg.aud != args.options.audience {
+        return Err("Invalid token: wrong audience".to_owned());
+    }
+    println!(
+        "Valid token for {}",
+        reg.sub.as_ref().map_or("no subject", |s| s)
+    );
+    if let Some(remaining) = context.remaining_lifetime() {
+        println!("Expires in {} seconds", remaining.num_seconds());
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! A bounded cache of verified tokens.
+//!
+//! Api clients often send the same token with every request.  With
+//! the cache, the signature of such a token is only checked and its
+//! claims only parsed once; later requests only get the cheap checks
+//! of `SessionCore::reverify()`, i.e. the times, audience and
+//! revocation of the token.
+
+use context::AuthContext;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// A least-recently-used cache of verified tokens, keyed by a hash
+/// of the token.
+///
+/// All clones of a `TokenCache` share the same entries.
+#[derive(Clone)]
+pub(crate) struct TokenCache {
+    capacity: usize,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// The key of each entry, by the time it was last used.
+    by_use: BTreeMap<u64, String>,
+    /// Increased for each use of the cache.
+    clock: u64,
+}
+
+struct Entry {
+    context: AuthContext,
+    /// The keyring generation when the token was verified.
+    generation: u64,
+    /// The time the entry was last used.
+    used: u64,
+}
+
+impl TokenCache {
+    /// Create a cache for at most `capacity` tokens.
+    pub(crate) fn new(capacity: usize) -> TokenCache {
+        TokenCache {
+            capacity: capacity,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Get a token verified with keys of the given `generation`.
+    pub(crate) fn get(
+        &self,
+        token: &str,
+        generation: u64,
+    ) -> Option<AuthContext> {
+        let key = hash(token);
+        let mut state = self.lock();
+        state.clock += 1;
+        let now = state.clock;
+        let (context, used) = match state.entries.get_mut(&key) {
+            Some(ref entry) if entry.generation != generation => (None, None),
+            Some(entry) => {
+                let used = ::std::mem::replace(&mut entry.used, now);
+                (Some(entry.context.clone()), Some(used))
+            }
+            None => return None,
+        };
+        match used {
+            Some(used) => {
+                state.by_use.remove(&used);
+                state.by_use.insert(now, key);
+            }
+            None => state.remove(&key),
+        }
+        context
+    }
+
+    /// Add a verified token, verified with keys of `generation`.
+    ///
+    /// If the cache is full, the least recently used token is removed.
+    pub(crate) fn insert(&self, context: &AuthContext, generation: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = hash(&context.token);
+        let mut state = self.lock();
+        state.remove(&key);
+        while state.entries.len() >= self.capacity {
+            let oldest = match state.by_use.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = state.by_use.remove(&oldest) {
+                state.entries.remove(&key);
+            }
+        }
+        state.clock += 1;
+        let now = state.clock;
+        state.by_use.insert(now, key.clone());
+        state.entries.insert(
+            key,
+            Entry {
+                context: context.clone(),
+                generation: generation,
+                used: now,
+            },
+        );
+    }
+
+    /// Remove a token, e.g. when it is expired or revoked.
+    pub(crate) fn remove(&self, token: &str) {
+        self.lock().remove(&hash(token));
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_use.remove(&entry.used);
+        }
+    }
+}
+
+fn hash(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::TokenHeader;
+    use jwt::{Header, Registered};
+
+    fn context(token: &str) -> AuthContext {
+        AuthContext {
+            token: token.to_owned(),
+            header: TokenHeader::Signed(Header::default()),
+            registered: Registered::default(),
+            custom_claims: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn least_recently_used_is_removed() {
+        let cache = TokenCache::new(2);
+        cache.insert(&context("a"), 0);
+        cache.insert(&context("b"), 0);
+        assert!(cache.get("a", 0).is_some());
+        cache.insert(&context("c"), 0);
+        assert!(cache.get("b", 0).is_none());
+        assert_eq!(cache.get("a", 0).map(|c| c.token), Some("a".into()));
+        assert_eq!(cache.get("c", 0).map(|c| c.token), Some("c".into()));
+    }
+
+    #[test]
+    fn new_keys_forget_tokens() {
+        let cache = TokenCache::new(2);
+        cache.insert(&context("a"), 0);
+        assert!(cache.get("a", 1).is_none());
+        assert!(cache.get("a", 0).is_none());
+    }
+}
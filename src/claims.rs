@@ -0,0 +1,172 @@
+//! Strongly typed custom claims.
+//!
+//! The jwt crate keeps private claims as rustc-serialize `Json`
+//! values.  This module converts between those and any type that
+//! implements the serde `Serialize` and `Deserialize` traits.
+
+use rustc_serialize::json::Json;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Number, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+/// An error converting custom claims to or from a typed value.
+#[derive(Debug)]
+pub enum ClaimsError {
+    /// The value could not be serialized or deserialized.
+    Json(serde_json::Error),
+    /// The value did not serialize to a json object, so it can't be
+    /// used as claims.
+    NotAnObject,
+}
+
+impl fmt::Display for ClaimsError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClaimsError::Json(ref err) => write!(out, "Bad claims: {}", err),
+            ClaimsError::NotAnObject => {
+                write!(out, "Claims must be a json object")
+            }
+        }
+    }
+}
+
+impl Error for ClaimsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ClaimsError::Json(ref err) => Some(err),
+            ClaimsError::NotAnObject => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for ClaimsError {
+    fn from(err: serde_json::Error) -> Self {
+        ClaimsError::Json(err)
+    }
+}
+
+/// Serialize a value to private claims.
+pub fn to_claims<T: Serialize>(
+    value: &T,
+) -> Result<BTreeMap<String, Json>, ClaimsError> {
+    match serde_json::to_value(value)? {
+        Value::Object(map) => {
+            Ok(map.into_iter().map(|(k, v)| (k, to_rustc_json(v))).collect())
+        }
+        _ => Err(ClaimsError::NotAnObject),
+    }
+}
+
+/// Deserialize a value from private claims.
+pub fn from_claims<T: DeserializeOwned>(
+    claims: &BTreeMap<String, Json>,
+) -> Result<T, ClaimsError> {
+    let map = claims
+        .iter()
+        .map(|(k, v)| (k.clone(), to_serde_json(v)))
+        .collect::<Map<String, Value>>();
+    Ok(serde_json::from_value(Value::Object(map))?)
+}
+
+fn to_rustc_json(value: Value) -> Json {
+    match value {
+        Value::Null => Json::Null,
+        Value::Bool(b) => Json::Boolean(b),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Json::I64(i)
+            } else if let Some(u) = n.as_u64() {
+                Json::U64(u)
+            } else {
+                n.as_f64().map(Json::F64).unwrap_or(Json::Null)
+            }
+        }
+        Value::String(s) => Json::String(s),
+        Value::Array(a) => {
+            Json::Array(a.into_iter().map(to_rustc_json).collect())
+        }
+        Value::Object(o) => Json::Object(
+            o.into_iter().map(|(k, v)| (k, to_rustc_json(v))).collect(),
+        ),
+    }
+}
+
+fn to_serde_json(json: &Json) -> Value {
+    match *json {
+        Json::Null => Value::Null,
+        Json::Boolean(b) => Value::Bool(b),
+        Json::I64(i) => Value::from(i),
+        Json::U64(u) => Value::from(u),
+        Json::F64(f) => {
+            Number::from_f64(f).map(Value::Number).unwrap_or(Value::Null)
+        }
+        Json::String(ref s) => Value::String(s.clone()),
+        Json::Array(ref a) => {
+            Value::Array(a.iter().map(to_serde_json).collect())
+        }
+        Json::Object(ref o) => Value::Object(
+            o.iter().map(|(k, v)| (k.clone(), to_serde_json(v))).collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_claims, to_claims, ClaimsError};
+    use serde_json::Value;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        admin: bool,
+        groups: Vec<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Other {
+        #[allow(dead_code)]
+        level: u8,
+    }
+
+    #[test]
+    fn roundtrip() {
+        let value: Value = ::serde_json::from_str(
+            r#"{"name": "carl", "admin": true, "n": -3, "f": 1.5,
+                "groups": ["a", "b"], "extra": {"x": null}}"#,
+        ).unwrap();
+        let claims = to_claims(&value).unwrap();
+        assert_eq!(from_claims::<Value>(&claims).unwrap(), value);
+    }
+
+    #[test]
+    fn not_an_object() {
+        assert!(to_claims(&vec![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn derived_roundtrip() {
+        let user = User {
+            name: "carl".to_owned(),
+            admin: false,
+            groups: vec!["a".to_owned(), "b".to_owned()],
+        };
+        let claims = to_claims(&user).unwrap();
+        assert_eq!(from_claims::<User>(&claims).unwrap(), user);
+    }
+
+    #[test]
+    fn mismatched_claims() {
+        let claims = to_claims(&User {
+            name: "carl".to_owned(),
+            admin: true,
+            groups: vec![],
+        }).unwrap();
+        match from_claims::<Other>(&claims) {
+            Err(ClaimsError::Json(_)) => (),
+            other => panic!("Expected a json error, got {:?}", other),
+        }
+    }
+}
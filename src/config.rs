@@ -0,0 +1,368 @@
+//! Configuration from environment variables or a toml file.
+//!
+//! The same settings are available in both sources.  In the
+//! environment, they are upper case with a prefix, e.g.
+//! `NICKEL_JWT_KEY_FILE`, and in toml they are lower case keys in a
+//! section, e.g. `key_file` in `[session]`.
+//!
+//! | Setting            | Value                                      |
+//! |--------------------|--------------------------------------------|
+//! | `key`              | The server key                             |
+//! | `key_file`         | Path to a file with the raw server key     |
+//! | `key_file_poll`    | Seconds between checks for a new key file  |
+//! | `issuer`           | The iss (issuer) claim                     |
+//! | `audience`         | The aud (audience) claim                   |
+//! | `expiration`       | Token lifetime in seconds                  |
+//! | `location`         | Comma-separated token locations (or a toml |
+//! |                    | array), e.g. `cookie:jwt, authorization`   |
+//! | `cookie_secure`    | `true` or `false`                          |
+//! | `cookie_http_only` | `true` or `false`                          |
+//! | `cookie_path`      | The path of token cookies                  |
+//! | `cookie_domain`    | The domain of token cookies                |
+//! | `encryption`       | `dir` or `A256KW` to encrypt tokens        |
+//!
+//! Exactly one of `key` and `key_file` is required, and the key must
+//! be at least `key::MIN_KEY_LENGTH` bytes.  All of the key file is
+//! used as the key, including any line break at the end.  With
+//! `key_file_poll`, the key file is checked for changes in a background
+//! thread, and the key is replaced when it changes (see
+//! `Keyring::watch_file()`).
+
+use jwe::Encryption;
+use key::read_key_file;
+use session_core::{SessionCore, TokenLocation};
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration as StdDuration;
+use time::Duration;
+use toml;
+
+const SETTINGS: &'static [&'static str] = &[
+    "key",
+    "key_file",
+    "key_file_poll",
+    "issuer",
+    "audience",
+    "expiration",
+    "location",
+    "cookie_secure",
+    "cookie_http_only",
+    "cookie_path",
+    "cookie_domain",
+    "encryption",
+];
+
+/// An error in the session configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A required setting is missing.
+    Missing(String),
+    /// The named setting has a bad value.
+    Invalid(String, String),
+    /// The named setting is not known.
+    Unknown(String),
+    /// A file could not be read.
+    Io(String, io::Error),
+    /// The toml file could not be parsed.
+    Toml(toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Missing(ref setting) => {
+                write!(out, "Missing setting {}", setting)
+            }
+            ConfigError::Invalid(ref setting, ref problem) => {
+                write!(out, "Bad setting {}: {}", setting, problem)
+            }
+            ConfigError::Unknown(ref setting) => {
+                write!(out, "Unknown setting {}", setting)
+            }
+            ConfigError::Io(ref file, ref err) => {
+                write!(out, "Failed to read {}: {}", file, err)
+            }
+            ConfigError::Toml(ref err) => write!(out, "Bad toml: {}", err),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ConfigError::Io(_, ref err) => Some(err),
+            ConfigError::Toml(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Raw setting values, by setting name, and how to name a setting
+/// in errors.
+struct Settings {
+    values: BTreeMap<&'static str, String>,
+    prefix: String,
+    upper: bool,
+}
+
+impl Settings {
+    fn name(&self, setting: &str) -> String {
+        if self.upper {
+            format!("{}{}", self.prefix, setting.to_uppercase())
+        } else {
+            format!("{}{}", self.prefix, setting)
+        }
+    }
+
+    fn get(&self, setting: &str) -> Option<&str> {
+        self.values.get(setting).map(|value| value.as_str())
+    }
+
+    fn invalid<E: fmt::Display>(&self, setting: &str, err: E) -> ConfigError {
+        ConfigError::Invalid(self.name(setting), err.to_string())
+    }
+
+    fn flag(&self, setting: &str) -> Result<Option<bool>, ConfigError> {
+        match self.get(setting) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|e| self.invalid(setting, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn core(&self) -> Result<SessionCore, ConfigError> {
+        let key = match (self.get("key"), self.get("key_file")) {
+            (Some(key), None) => key.as_bytes().to_owned(),
+            (None, Some(path)) => read_key_file(path)
+                .map_err(|e| ConfigError::Io(self.name("key_file"), e))?,
+            (Some(_), Some(_)) => {
+                return Err(self.invalid("key_file", "key is also set"));
+            }
+            (None, None) => return Err(ConfigError::Missing(self.name("key"))),
+        };
+        let mut core = SessionCore::try_new(&key).map_err(|e| {
+            let setting = if self.get("key").is_some() {
+                "key"
+            } else {
+                "key_file"
+            };
+            self.invalid(setting, e)
+        })?;
+        let mut watch = None;
+        if let Some(poll) = self.get("key_file_poll") {
+            let seconds = poll
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| self.invalid("key_file_poll", e))?;
+            match self.get("key_file") {
+                Some(path) if seconds > 0 => {
+                    watch = Some((path, StdDuration::from_secs(seconds)));
+                }
+                Some(_) => {
+                    let problem = "must be positive";
+                    return Err(self.invalid("key_file_poll", problem));
+                }
+                None => {
+                    let problem = "needs key_file";
+                    return Err(self.invalid("key_file_poll", problem));
+                }
+            }
+        }
+        if let Some(issuer) = self.get("issuer") {
+            core = core.issuer(issuer);
+        }
+        if let Some(audience) = self.get("audience") {
+            core = core.audience(audience);
+        }
+        if let Some(expiration) = self.get("expiration") {
+            let seconds = expiration
+                .trim()
+                .parse::<u32>()
+                .map_err(|e| self.invalid("expiration", e))?;
+            if seconds == 0 {
+                return Err(self.invalid("expiration", "must be positive"));
+            }
+            core = core.expiration_time(Duration::seconds(seconds.into()));
+        }
+        if let Some(locations) = self.get("location") {
+            let mut locations = locations.split(',');
+            let first = locations.next().unwrap_or("");
+            core = core.using(
+                first.parse().map_err(|e| self.invalid("location", e))?,
+            );
+            for location in locations {
+                let location = location
+                    .parse::<TokenLocation>()
+                    .map_err(|e| self.invalid("location", e))?;
+                core = core.also_using(location);
+            }
+        }
+        if let Some(secure) = self.flag("cookie_secure")? {
+            core = core.cookie_secure(secure);
+        }
+        if let Some(http_only) = self.flag("cookie_http_only")? {
+            core = core.cookie_http_only(http_only);
+        }
+        if let Some(path) = self.get("cookie_path") {
+            core = core.cookie_path(path);
+        }
+        if let Some(domain) = self.get("cookie_domain") {
+            core = core.cookie_domain(domain);
+        }
+        if let Some(encryption) = self.get("encryption") {
+            let encryption = encryption
+                .parse::<Encryption>()
+                .map_err(|e| self.invalid("encryption", e))?;
+            core = core.encrypt_tokens(encryption);
+        }
+        // Only start watching the key file when all settings are
+        // valid, so a bad setting doesn't leave a thread behind.
+        if let Some((path, interval)) = watch {
+            core.keyring().watch_file(path, interval);
+        }
+        Ok(core)
+    }
+}
+
+impl SessionCore {
+    /// Create a new instance from environment variables.
+    ///
+    /// The variables are the settings in upper case, with the given
+    /// prefix, e.g. `NICKEL_JWT_KEY` for the prefix `NICKEL_JWT_`.
+    pub fn from_env(prefix: &str) -> Result<SessionCore, ConfigError> {
+        let mut values = BTreeMap::new();
+        for setting in SETTINGS {
+            let name = format!("{}{}", prefix, setting.to_uppercase());
+            match env::var(&name) {
+                Ok(value) => {
+                    values.insert(*setting, value);
+                }
+                Err(env::VarError::NotPresent) => {}
+                Err(err) => {
+                    return Err(ConfigError::Invalid(name, err.to_string()))
+                }
+            }
+        }
+        Settings {
+            values: values,
+            prefix: prefix.to_owned(),
+            upper: true,
+        }.core()
+    }
+
+    /// Create a new instance from a section of a toml file.
+    pub fn from_toml_file<P: AsRef<Path>>(
+        path: P,
+        section: &str,
+    ) -> Result<SessionCore, ConfigError> {
+        let path = path.as_ref();
+        let mut toml = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut toml))
+            .map_err(|e| ConfigError::Io(path.display().to_string(), e))?;
+        SessionCore::from_toml(&toml, section)
+    }
+
+    /// Create a new instance from a section of a toml document.
+    pub fn from_toml(
+        toml: &str,
+        section: &str,
+    ) -> Result<SessionCore, ConfigError> {
+        let doc = toml.parse::<toml::Value>().map_err(ConfigError::Toml)?;
+        let table = match doc.get(section) {
+            Some(&toml::Value::Table(ref table)) => table,
+            Some(_) => {
+                return Err(ConfigError::Invalid(
+                    section.to_owned(),
+                    "not a table".to_owned(),
+                ))
+            }
+            None => return Err(ConfigError::Missing(section.to_owned())),
+        };
+        let prefix = format!("{}.", section);
+        let mut values = BTreeMap::new();
+        for (name, value) in table {
+            let setting = match SETTINGS.iter().find(|s| *s == name) {
+                Some(setting) => *setting,
+                None => {
+                    return Err(ConfigError::Unknown(
+                        format!("{}{}", prefix, name),
+                    ))
+                }
+            };
+            let value = toml_string(value).ok_or_else(|| {
+                ConfigError::Invalid(
+                    format!("{}{}", prefix, name),
+                    format!("unexpected {}", value.type_str()),
+                )
+            })?;
+            values.insert(setting, value);
+        }
+        Settings {
+            values: values,
+            prefix: prefix,
+            upper: false,
+        }.core()
+    }
+}
+
+/// Get a toml string, integer, boolean or array of strings as a
+/// string setting.
+fn toml_string(value: &toml::Value) -> Option<String> {
+    match *value {
+        toml::Value::String(ref s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Array(ref items) => items
+            .iter()
+            .map(|item| item.as_str())
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(",")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_errors_name_the_setting() {
+        let toml = "[session]\nkey = \"a secret key that is long enough\"\n\
+                    expiration = -1\n";
+        match SessionCore::from_toml(toml, "session") {
+            Err(ConfigError::Invalid(setting, _)) => {
+                assert_eq!(setting, "session.expiration")
+            }
+            _ => panic!("Expected an invalid setting"),
+        }
+        match SessionCore::from_toml("[session]\nkye = \"x\"\n", "session") {
+            Err(ConfigError::Unknown(setting)) => {
+                assert_eq!(setting, "session.kye")
+            }
+            _ => panic!("Expected an unknown setting"),
+        }
+    }
+
+    #[test]
+    fn toml_locations() {
+        let toml = "[session]\nkey = \"a secret key that is long enough\"\n\
+                    location = [\"cookie:sid\", \"authorization\"]\n";
+        let core = SessionCore::from_toml(toml, "session").unwrap();
+        assert_eq!(
+            core.locations(),
+            &[
+                TokenLocation::Cookie("sid".to_owned()),
+                TokenLocation::AuthorizationHeader,
+            ]
+        );
+    }
+}
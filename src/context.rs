@@ -0,0 +1,123 @@
+//! Full information about the valid token of a request.
+
+use claims::{self, ClaimsError};
+use jwt::{Algorithm, Header, HeaderType, Registered};
+use redact::fingerprint;
+use rustc_serialize::json::Json;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::fmt;
+use time::Duration;
+
+/// All data of a valid token, for auditing and for forwarding to
+/// downstream services.
+///
+/// This is available through the `auth_context()` request method
+/// when the request has a valid token.
+pub struct AuthContext {
+    /// The token itself, as received.
+    pub token: String,
+    /// The header of the token.
+    pub header: TokenHeader,
+    /// The registered claims (`iss`, `sub`, `aud`, `exp`, `nbf`,
+    /// `iat` and `jti`).
+    pub registered: Registered,
+    /// The custom claims.
+    pub custom_claims: BTreeMap<String, Json>,
+}
+
+/// The header of a token.
+#[derive(Debug)]
+pub enum TokenHeader {
+    /// The jwt header of a signed token (algorithm, type and key id).
+    Signed(Header),
+    /// The protected header of an encrypted token, with `alg`, `enc`
+    /// and maybe `cty` and `kid`.
+    ///
+    /// For a nested token, this is the header of the outer, encrypted
+    /// token.
+    Encrypted(BTreeMap<String, Json>),
+}
+
+impl TokenHeader {
+    /// Get the kid (key id) of the token, if any.
+    pub fn key_id(&self) -> Option<&str> {
+        match *self {
+            TokenHeader::Signed(ref header) => {
+                header.kid.as_ref().map(|kid| kid.as_str())
+            }
+            TokenHeader::Encrypted(ref header) => {
+                header.get("kid").and_then(Json::as_string)
+            }
+        }
+    }
+}
+
+impl Clone for TokenHeader {
+    // The jwt header, its type and its algorithm are not Clone.
+    fn clone(&self) -> TokenHeader {
+        match *self {
+            TokenHeader::Signed(ref header) => TokenHeader::Signed(Header {
+                typ: header.typ.as_ref().map(|_| HeaderType::JWT),
+                kid: header.kid.clone(),
+                alg: match header.alg {
+                    Algorithm::HS256 => Algorithm::HS256,
+                    Algorithm::RS256 => Algorithm::RS256,
+                },
+            }),
+            TokenHeader::Encrypted(ref header) => {
+                TokenHeader::Encrypted(header.clone())
+            }
+        }
+    }
+}
+
+impl Clone for AuthContext {
+    // The registered claims are not Clone.
+    fn clone(&self) -> AuthContext {
+        let reg = &self.registered;
+        AuthContext {
+            token: self.token.clone(),
+            header: self.header.clone(),
+            registered: Registered {
+                iss: reg.iss.clone(),
+                sub: reg.sub.clone(),
+                aud: reg.aud.clone(),
+                exp: reg.exp,
+                nbf: reg.nbf,
+                iat: reg.iat,
+                jti: reg.jti.clone(),
+            },
+            custom_claims: self.custom_claims.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for AuthContext {
+    // Only a fingerprint of the token, so it can't leak through logs.
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.debug_struct("AuthContext")
+            .field("token", &fingerprint(&self.token))
+            .field("header", &self.header)
+            .field("registered", &self.registered)
+            .field("custom_claims", &self.custom_claims)
+            .finish()
+    }
+}
+
+impl AuthContext {
+    /// Get the remaining lifetime of the token.
+    ///
+    /// Returns None if the token has no `exp` claim.
+    pub fn remaining_lifetime(&self) -> Option<Duration> {
+        let now = ::current_numeric_date();
+        self.registered.exp.map(|exp| {
+            Duration::seconds(exp as i64 - now as i64)
+        })
+    }
+
+    /// Deserialize the custom claims to a typed value.
+    pub fn claims<T: DeserializeOwned>(&self) -> Result<T, ClaimsError> {
+        claims::from_claims(&self.custom_claims)
+    }
+}
@@ -0,0 +1,100 @@
+//! Double-submit csrf protection for cookie-based sessions.
+//!
+//! A browser sends the session cookie with any request to the site,
+//! including requests triggered by other sites.  With csrf protection
+//! enabled, state-changing requests with a cookie session must also
+//! submit a csrf token, in a header or a form field.  The csrf token
+//! is derived from the `jti` of the session token, so it is bound to
+//! the session and can't be guessed by another site.
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rustc_serialize::hex::ToHex;
+#[cfg(feature = "middleware")]
+use session_core::TokenLocation;
+
+/// Configuration for csrf protection.
+#[derive(Clone, Debug)]
+pub struct CsrfProtection {
+    /// Name of the header that can hold the csrf token.
+    header: String,
+    /// Name of the form field that can hold the csrf token.
+    field: String,
+}
+
+impl CsrfProtection {
+    /// Create a new instance.
+    ///
+    /// By default, the token is looked for in the `X-CSRF-Token`
+    /// header and in the `csrf_token` form field.
+    pub fn new() -> CsrfProtection {
+        CsrfProtection {
+            header: "X-CSRF-Token".to_owned(),
+            field: "csrf_token".to_owned(),
+        }
+    }
+
+    /// Set the name of the header that can hold the csrf token.
+    pub fn header(mut self, header: &str) -> Self {
+        self.header = header.to_owned();
+        self
+    }
+
+    /// Set the name of the form field that can hold the csrf token.
+    pub fn field(mut self, field: &str) -> Self {
+        self.field = field.to_owned();
+        self
+    }
+
+    /// Get the name of the header that can hold the csrf token.
+    pub fn header_name(&self) -> &str {
+        &self.header
+    }
+
+    /// Get the name of the form field that can hold the csrf token.
+    pub fn field_name(&self) -> &str {
+        &self.field
+    }
+}
+
+impl Default for CsrfProtection {
+    fn default() -> Self {
+        CsrfProtection::new()
+    }
+}
+
+/// Derive the csrf token for a session from its `jti`.
+pub(crate) fn csrf_token(key: &[u8], jti: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(b"csrf:");
+    hmac.input(jti.as_bytes());
+    hmac.result().code().to_hex()
+}
+
+/// Check if csrf protection applies to a token from `location`.
+///
+/// Only cookies are sent by the browser on its own; a token in a
+/// header or query parameter must be added by the client itself.
+#[cfg(feature = "middleware")]
+pub(crate) fn applies_to(location: &TokenLocation) -> bool {
+    match *location {
+        TokenLocation::Cookie(_) => true,
+        _ => false,
+    }
+}
+
+/// Check if a request method is one that needs csrf protection.
+pub(crate) fn is_state_changing(method: &str) -> bool {
+    match method {
+        "POST" | "PUT" | "PATCH" | "DELETE" => true,
+        _ => false,
+    }
+}
+
+/// Compare a submitted csrf token to the expected one in fixed time.
+pub(crate) fn is_valid(expected: &str, submitted: &str) -> bool {
+    !expected.is_empty() &&
+        fixed_time_eq(expected.as_bytes(), submitted.as_bytes())
+}
@@ -0,0 +1,72 @@
+//! The error type for setting and clearing tokens.
+
+use claims::ClaimsError;
+use jwt;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use time::Duration;
+
+/// An error setting or clearing a token on a response.
+#[derive(Debug)]
+pub enum SessionError {
+    /// There is no `SessionMiddleware` on the response, i.e. the route
+    /// is not handled by a server that utilizes the middleware.
+    NoMiddleware,
+    /// The token could not be signed.
+    Signing(jwt::Error),
+    /// The token could not be encrypted, as no random data was
+    /// available.
+    Encryption(io::Error),
+    /// A nested token was requested, but there is no `NestedTokens`
+    /// signing key.
+    NoNestedKey,
+    /// Typed claims could not be converted to custom claims.
+    Claims(ClaimsError),
+    /// A header name or value could not be used in a response.
+    InvalidHeader(String),
+    /// The expiration time of a new token is not positive.
+    BadExpiration(Duration),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SessionError::NoMiddleware => {
+                write!(out, "No SessionMiddleware on response")
+            }
+            SessionError::Signing(ref err) => {
+                write!(out, "Failed to sign token: {:?}", err)
+            }
+            SessionError::Encryption(ref err) => {
+                write!(out, "Failed to encrypt token: {}", err)
+            }
+            SessionError::NoNestedKey => {
+                write!(out, "No signing key for nested tokens")
+            }
+            SessionError::Claims(ref err) => err.fmt(out),
+            SessionError::InvalidHeader(ref header) => {
+                write!(out, "Invalid header {:?}", header)
+            }
+            SessionError::BadExpiration(ref expiration) => {
+                write!(out, "Bad token expiration time {}", expiration)
+            }
+        }
+    }
+}
+
+impl Error for SessionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SessionError::Claims(ref err) => Some(err),
+            SessionError::Encryption(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ClaimsError> for SessionError {
+    fn from(err: ClaimsError) -> Self {
+        SessionError::Claims(err)
+    }
+}
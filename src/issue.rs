@@ -0,0 +1,98 @@
+//! Builder for issuing a token with per-call options.
+
+use error::SessionError;
+use middleware::set_new_jwt;
+use nickel::Response;
+use rustc_serialize::json::Json;
+use session_core::TokenOptions;
+use std::collections::BTreeMap;
+use time::Duration;
+
+/// A builder for a token to set on a response.
+///
+/// Created by the `issue_jwt()` response method.  Use it when a token
+/// needs other options than the defaults of the `SessionMiddleware`,
+/// e.g. a longer lifetime for a "remember me" login or a shorter one
+/// for an elevated session:
+///
+/// ```ignore
+/// res.issue_jwt()
+///     .subject("carl")
+///     .expires_in(Duration::days(30))
+///     .claim("remember", true.to_json())
+///     .set()?;
+/// ```
+///
+/// Of the token header, only the `kid` can be set, by `key_id()`.
+/// The `Header` of jwt 0.4 has no room for other fields, so headers
+/// like `x5t` or custom ones can't be added.
+pub struct TokenBuilder<'r, R: 'r> {
+    response: &'r mut R,
+    options: TokenOptions,
+}
+
+impl<'r, R> TokenBuilder<'r, R> {
+    pub(crate) fn new(response: &'r mut R) -> Self {
+        TokenBuilder {
+            response: response,
+            options: TokenOptions::default(),
+        }
+    }
+
+    /// Set the sub (subject) claim, i.e. the username.
+    pub fn subject(mut self, subject: &str) -> Self {
+        self.options.subject = Some(subject.to_owned());
+        self
+    }
+
+    /// Set the aud (audience) claim.
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.options.audience = Some(audience.to_owned());
+        self
+    }
+
+    /// Set how long the token should be valid after creation.
+    ///
+    /// The default is the `expiration_time` of the `SessionMiddleware`.
+    pub fn expires_in(mut self, expiration: Duration) -> Self {
+        self.options.expiration = Some(expiration);
+        self
+    }
+
+    /// Set the kid (key id) header.
+    ///
+    /// This is the only header field that can be set per token.
+    pub fn key_id(mut self, key_id: &str) -> Self {
+        self.options.key_id = Some(key_id.to_owned());
+        self
+    }
+
+    /// Create a nested token, to share with partner services.
+    ///
+    /// This requires a `NestedTokens` with a signing key on the
+    /// `SessionMiddleware`.
+    pub fn nested(mut self) -> Self {
+        self.options.nested = true;
+        self
+    }
+
+    /// Add a custom claim.
+    pub fn claim(mut self, name: &str, value: Json) -> Self {
+        self.options.claims.insert(name.to_owned(), value);
+        self
+    }
+
+    /// Add several custom claims.
+    pub fn claims(mut self, claims: BTreeMap<String, Json>) -> Self {
+        self.options.claims.extend(claims);
+        self
+    }
+}
+
+impl<'r, 'a, D> TokenBuilder<'r, Response<'a, D>> {
+    /// Create the token and set it on the response.
+    pub fn set(self) -> Result<(), SessionError> {
+        debug!("Should set a jwt for {:?}", self.options.subject);
+        set_new_jwt(self.response, self.options)
+    }
+}
@@ -0,0 +1,316 @@
+//! Encrypted tokens, in the JWE compact serialization of RFC 7516.
+//!
+//! The content is encrypted with AES-256-GCM (`enc` `A256GCM`), and
+//! the content encryption key is either the encryption key itself
+//! (`alg` `dir`), or a random key wrapped with the encryption key by
+//! the AES key wrap of RFC 3394 (`alg` `A256KW`).
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::aessafe::{AesSafe256Decryptor, AesSafe256Encryptor};
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+use crypto::util::fixed_time_eq;
+use key;
+use rand::{OsRng, Rng};
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// How to encrypt tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encryption {
+    /// Encrypt the content directly with the encryption key (`dir`).
+    Direct,
+    /// Encrypt the content with a random key for each token, and
+    /// include that key wrapped with the encryption key (`A256KW`).
+    KeyWrap,
+}
+
+impl Encryption {
+    fn alg(&self) -> &'static str {
+        match *self {
+            Encryption::Direct => "dir",
+            Encryption::KeyWrap => "A256KW",
+        }
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    /// Parse an encryption from its `alg` name, `dir` or `A256KW`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dir" => Ok(Encryption::Direct),
+            "A256KW" => Ok(Encryption::KeyWrap),
+            _ => Err(format!("unknown encryption {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.write_str(self.alg())
+    }
+}
+
+/// A decrypted token.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Decrypted {
+    pub(crate) plaintext: Vec<u8>,
+    /// The protected header, with `alg`, `enc` and maybe `cty` and
+    /// `kid`.
+    pub(crate) header: BTreeMap<String, Json>,
+}
+
+impl Decrypted {
+    /// Get the `cty` (content type) header, if any.
+    pub(crate) fn cty(&self) -> Option<&str> {
+        self.header.get("cty").and_then(Json::as_string)
+    }
+}
+
+/// The reason a token could not be decrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DecryptError {
+    /// The token is not a JWE with a supported `alg` and `enc`.
+    Malformed,
+    /// The token was not encrypted with the key, or was altered.
+    BadKey,
+}
+
+const ENC: &'static str = "A256GCM";
+pub(crate) const KEY_LENGTH: usize = 32;
+const IV_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// Get the encryption key for a server key.
+///
+/// The server key is used for signing, so a separate key for
+/// encryption is derived from it.
+pub(crate) fn encryption_key(server_key: &[u8]) -> Vec<u8> {
+    key::derive_from_key_material(server_key, b"", b"jwt session jwe")
+}
+
+/// Check if a token looks like a JWE rather than a signed JWT.
+pub(crate) fn is_encrypted(token: &str) -> bool {
+    token.split('.').count() == 5
+}
+
+/// Encrypt `plaintext` to a token.
+///
+/// The `cty` (content type) header is set if given, e.g. `JWT` for a
+/// signed token in the encrypted one, and so is the `kid` (key id).
+pub(crate) fn encrypt(
+    encryption: Encryption,
+    key: &[u8],
+    cty: Option<&str>,
+    kid: Option<&str>,
+    plaintext: &[u8],
+) -> Result<String, io::Error> {
+    let mut rng = OsRng::new()?;
+    let (cek, encrypted_key) = match encryption {
+        Encryption::Direct => (key.to_owned(), Vec::new()),
+        Encryption::KeyWrap => {
+            let mut cek = vec![0u8; KEY_LENGTH];
+            rng.fill_bytes(&mut cek);
+            let wrapped = wrap_key(key, &cek);
+            (cek, wrapped)
+        }
+    };
+    let mut header = BTreeMap::new();
+    header.insert("alg".to_owned(), Json::String(encryption.alg().into()));
+    header.insert("enc".to_owned(), Json::String(ENC.to_owned()));
+    if let Some(cty) = cty {
+        header.insert("cty".to_owned(), Json::String(cty.to_owned()));
+    }
+    if let Some(kid) = kid {
+        header.insert("kid".to_owned(), Json::String(kid.to_owned()));
+    }
+    let header = Json::Object(header).to_string();
+    let header = header.as_bytes().to_base64(URL_SAFE);
+
+    let mut iv = [0u8; IV_LENGTH];
+    rng.fill_bytes(&mut iv);
+    let mut ciphertext = vec![0u8; plaintext.len()];
+    let mut tag = [0u8; TAG_LENGTH];
+    AesGcm::new(KeySize::KeySize256, &cek, &iv, header.as_bytes())
+        .encrypt(plaintext, &mut ciphertext, &mut tag);
+    Ok(format!(
+        "{}.{}.{}.{}.{}",
+        header,
+        encrypted_key.to_base64(URL_SAFE),
+        iv.to_base64(URL_SAFE),
+        ciphertext.to_base64(URL_SAFE),
+        tag.to_base64(URL_SAFE),
+    ))
+}
+
+/// Decrypt a token.
+pub(crate) fn decrypt(
+    key: &[u8],
+    token: &str,
+) -> Result<Decrypted, DecryptError> {
+    let parts = token.split('.').collect::<Vec<_>>();
+    if parts.len() != 5 {
+        return Err(DecryptError::Malformed);
+    }
+    let decode = |part: &str| {
+        part.from_base64().map_err(|_| DecryptError::Malformed)
+    };
+    let header = String::from_utf8(decode(parts[0])?)
+        .ok()
+        .and_then(|header| Json::from_str(&header).ok())
+        .and_then(|header| header.into_object())
+        .ok_or(DecryptError::Malformed)?;
+    let field = |name| header.get(name).and_then(Json::as_string);
+    if field("enc") != Some(ENC) {
+        return Err(DecryptError::Malformed);
+    }
+    let encryption = field("alg")
+        .and_then(|alg| alg.parse().ok())
+        .ok_or(DecryptError::Malformed)?;
+    let encrypted_key = decode(parts[1])?;
+    let cek = match encryption {
+        Encryption::Direct if encrypted_key.is_empty() => key.to_owned(),
+        Encryption::Direct => return Err(DecryptError::Malformed),
+        Encryption::KeyWrap => unwrap_key(key, &encrypted_key)?,
+    };
+    let iv = decode(parts[2])?;
+    let ciphertext = decode(parts[3])?;
+    let tag = decode(parts[4])?;
+    if cek.len() != KEY_LENGTH ||
+        iv.len() != IV_LENGTH ||
+        tag.len() != TAG_LENGTH
+    {
+        return Err(DecryptError::Malformed);
+    }
+    let mut plaintext = vec![0u8; ciphertext.len()];
+    let mut gcm =
+        AesGcm::new(KeySize::KeySize256, &cek, &iv, parts[0].as_bytes());
+    if !gcm.decrypt(&ciphertext, &mut plaintext, &tag) {
+        return Err(DecryptError::BadKey);
+    }
+    Ok(Decrypted {
+        plaintext: plaintext,
+        header: header,
+    })
+}
+
+/// The initial value of RFC 3394 section 2.2.3.1.
+const KEY_WRAP_IV: [u8; 8] = [0xA6; 8];
+
+/// Wrap a key with the AES key wrap of RFC 3394.
+///
+/// The length of the key must be a multiple of 8 bytes.
+fn wrap_key(kek: &[u8], key: &[u8]) -> Vec<u8> {
+    let aes = AesSafe256Encryptor::new(kek);
+    let mut a = KEY_WRAP_IV;
+    let mut r = key.chunks(8).map(block).collect::<Vec<_>>();
+    let n = r.len();
+    let (mut input, mut output) = ([0u8; 16], [0u8; 16]);
+    for j in 0..6 {
+        for i in 0..n {
+            input[..8].copy_from_slice(&a);
+            input[8..].copy_from_slice(&r[i]);
+            aes.encrypt_block(&input, &mut output);
+            a = xor_counter(&output[..8], (n * j + i + 1) as u64);
+            r[i] = block(&output[8..]);
+        }
+    }
+    let mut result = a.to_vec();
+    for block in r {
+        result.extend_from_slice(&block);
+    }
+    result
+}
+
+/// Unwrap a key wrapped with the AES key wrap of RFC 3394.
+fn unwrap_key(kek: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if wrapped.len() < 24 || wrapped.len() % 8 != 0 {
+        return Err(DecryptError::Malformed);
+    }
+    let aes = AesSafe256Decryptor::new(kek);
+    let mut a = block(&wrapped[..8]);
+    let mut r = wrapped[8..].chunks(8).map(block).collect::<Vec<_>>();
+    let n = r.len();
+    let (mut input, mut output) = ([0u8; 16], [0u8; 16]);
+    for j in (0..6).rev() {
+        for i in (0..n).rev() {
+            let t = (n * j + i + 1) as u64;
+            input[..8].copy_from_slice(&xor_counter(&a, t));
+            input[8..].copy_from_slice(&r[i]);
+            aes.decrypt_block(&input, &mut output);
+            a = block(&output[..8]);
+            r[i] = block(&output[8..]);
+        }
+    }
+    if !fixed_time_eq(&a, &KEY_WRAP_IV) {
+        return Err(DecryptError::BadKey);
+    }
+    Ok(r.iter().flat_map(|block| block.iter().cloned()).collect())
+}
+
+fn block(data: &[u8]) -> [u8; 8] {
+    let mut block = [0u8; 8];
+    block.copy_from_slice(data);
+    block
+}
+
+fn xor_counter(data: &[u8], t: u64) -> [u8; 8] {
+    let mut result = block(data);
+    for (i, byte) in result.iter_mut().enumerate() {
+        *byte ^= (t >> (56 - 8 * i)) as u8;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    #[test]
+    fn key_wrap_rfc3394_vector() {
+        // RFC 3394 section 4.6, 256 bits of key data with a 256-bit KEK.
+        let kek = "000102030405060708090A0B0C0D0E0F\
+                   101112131415161718191A1B1C1D1E1F"
+            .from_hex()
+            .unwrap();
+        let key = "00112233445566778899AABBCCDDEEFF\
+                   000102030405060708090A0B0C0D0E0F"
+            .from_hex()
+            .unwrap();
+        let wrapped = wrap_key(&kek, &key);
+        assert_eq!(
+            wrapped.to_hex(),
+            "28c9f404c4b810f4cbccb35cfb87f8263f5786e2d80ed326\
+             cbc7f0e71a99f43bfb988b9b7a02dd21"
+        );
+        assert_eq!(unwrap_key(&kek, &wrapped), Ok(key));
+        assert_eq!(unwrap_key(&[7; 32], &wrapped), Err(DecryptError::BadKey));
+    }
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let key = [42; 32];
+        for &encryption in &[Encryption::Direct, Encryption::KeyWrap] {
+            let plaintext = b"{\"admin\":true}";
+            let token =
+                encrypt(encryption, &key, Some("JWT"), Some("k1"), plaintext)
+                    .unwrap();
+            assert!(is_encrypted(&token));
+            assert!(!token.contains("admin"));
+            let decrypted = decrypt(&key, &token).unwrap();
+            assert_eq!(decrypted.plaintext, plaintext.to_vec());
+            assert_eq!(decrypted.cty(), Some("JWT"));
+            let kid = decrypted.header.get("kid").and_then(Json::as_string);
+            assert_eq!(kid, Some("k1"));
+            assert_eq!(decrypt(&[7; 32], &token), Err(DecryptError::BadKey));
+        }
+    }
+}
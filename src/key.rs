@@ -0,0 +1,126 @@
+//! Server keys: strength checks, derivation and loading.
+//!
+//! Tokens are signed with HMAC-SHA256, so a key should be at least as
+//! long as the 32 byte hash.  A human passphrase is not a good key in
+//! itself, but a strong key can be derived from one with PBKDF2, and
+//! from other input key material with HKDF.
+
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The minimum length of a server key, in bytes.
+///
+/// This is the output size of SHA-256, as recommended for HMAC keys
+/// by RFC 7518 section 3.2.
+pub const MIN_KEY_LENGTH: usize = 32;
+
+/// An error for an unusable server key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyError {
+    /// The key is shorter than `MIN_KEY_LENGTH`.  The length of the
+    /// key is included.
+    TooShort(usize),
+    /// The key does not have the length needed for its use.  The
+    /// length of the key and the needed length are included.
+    WrongLength(usize, usize),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyError::TooShort(len) => write!(
+                out,
+                "Key is {} bytes, at least {} bytes are needed",
+                len,
+                MIN_KEY_LENGTH
+            ),
+            KeyError::WrongLength(len, needed) => write!(
+                out,
+                "Key is {} bytes, exactly {} bytes are needed",
+                len,
+                needed
+            ),
+        }
+    }
+}
+
+impl Error for KeyError {}
+
+/// Check that a key is strong enough.
+pub fn check_key(key: &[u8]) -> Result<(), KeyError> {
+    if key.len() < MIN_KEY_LENGTH {
+        Err(KeyError::TooShort(key.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Derive a key from a passphrase with PBKDF2-HMAC-SHA256.
+///
+/// The salt should be random, at least 16 bytes, and stored with the
+/// configuration.  The number of iterations should be as high as
+/// startup time allows, at least 100 000.
+pub fn derive_from_passphrase(
+    passphrase: &str,
+    salt: &[u8],
+    iterations: u32,
+) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), passphrase.as_bytes());
+    let mut key = vec![0u8; MIN_KEY_LENGTH];
+    pbkdf2(&mut mac, salt, iterations, &mut key);
+    key
+}
+
+/// Derive a key from input key material with HKDF-SHA256.
+///
+/// This is for input that is already random, such as a master key
+/// shared with other uses.  The `info` should be distinct for each
+/// use, e.g. `b"jwt session"`.
+pub fn derive_from_key_material(
+    material: &[u8],
+    salt: &[u8],
+    info: &[u8],
+) -> Vec<u8> {
+    let mut prk = [0u8; 32];
+    hkdf_extract(Sha256::new(), salt, material, &mut prk);
+    let mut key = vec![0u8; MIN_KEY_LENGTH];
+    hkdf_expand(Sha256::new(), &prk, info, &mut key);
+    key
+}
+
+/// Read raw key bytes from a file.
+///
+/// The whole content of the file is the key, including any line
+/// break at the end.
+pub fn read_key_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, io::Error> {
+    let mut key = Vec::new();
+    File::open(path)?.read_to_end(&mut key)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_keys_are_rejected() {
+        let short = b"My very secret key";
+        assert_eq!(check_key(short), Err(KeyError::TooShort(18)));
+        assert_eq!(check_key(&[7; 32]), Ok(()));
+    }
+
+    #[test]
+    fn derived_keys_are_strong() {
+        let key = derive_from_passphrase("correct horse", b"some salt", 10);
+        assert_eq!(check_key(&key), Ok(()));
+        let key = derive_from_key_material(b"master", b"salt", b"jwt");
+        assert_eq!(check_key(&key), Ok(()));
+    }
+}
@@ -0,0 +1,153 @@
+//! A shared, replaceable set of server keys.
+//!
+//! All clones of a `Keyring` share the same keys, so when a key is
+//! replaced, e.g. by a file watcher, every `SessionCore` and
+//! `SessionMiddleware` using it switches to the new key at once.
+
+use key::{self, read_key_file, KeyError};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// The current key, used for signing and verifying, and the previous
+/// key, only used for verifying.
+#[derive(Debug)]
+struct Keys {
+    current: Vec<u8>,
+    previous: Option<Vec<u8>>,
+    /// The number of times the key has been replaced.
+    generation: u64,
+}
+
+/// A set of server keys that can be replaced at runtime.
+///
+/// New tokens are signed with the current key.  When the key is
+/// replaced, the old key is kept for verifying tokens signed before
+/// the replacement, until the next replacement.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    keys: Arc<RwLock<Keys>>,
+}
+
+impl Keyring {
+    /// Create a new keyring with the given key.
+    pub fn new(key: &[u8]) -> Result<Keyring, KeyError> {
+        key::check_key(key)?;
+        Ok(Keyring::with_weak_key(key))
+    }
+
+    /// Create a new keyring without checking the key strength.
+    pub(crate) fn with_weak_key(key: &[u8]) -> Keyring {
+        Keyring {
+            keys: Arc::new(RwLock::new(Keys {
+                current: key.to_owned(),
+                previous: None,
+                generation: 0,
+            })),
+        }
+    }
+
+    /// Replace the current key.
+    ///
+    /// The old key is still accepted for verifying tokens, until the
+    /// key is replaced again.  Setting the current key again has no
+    /// effect.
+    pub fn replace(&self, key: &[u8]) -> Result<(), KeyError> {
+        key::check_key(key)?;
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        if keys.current != key {
+            let old = ::std::mem::replace(&mut keys.current, key.to_owned());
+            keys.previous = Some(old);
+            keys.generation += 1;
+            info!("Replaced the server key");
+        }
+        Ok(())
+    }
+
+    /// Get the current key, for signing.
+    pub(crate) fn current(&self) -> Vec<u8> {
+        self.keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .current
+            .clone()
+    }
+
+    /// Get all keys that are accepted for verifying, current first.
+    pub(crate) fn accepted(&self) -> Vec<Vec<u8>> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let mut result = vec![keys.current.clone()];
+        result.extend(keys.previous.iter().cloned());
+        result
+    }
+
+    /// Get the number of times the key has been replaced.
+    ///
+    /// Used to forget verified tokens when the accepted keys change.
+    #[cfg(feature = "middleware")]
+    pub(crate) fn generation(&self) -> u64 {
+        self.keys.read().unwrap_or_else(|e| e.into_inner()).generation
+    }
+
+    /// Poll a key file, and replace the key when the file changes.
+    ///
+    /// The file is checked every `interval` in a background thread.
+    /// If the file can't be read or has a weak key, a warning is
+    /// logged and the current key is kept.  The thread ends when all
+    /// clones of the keyring are dropped.
+    pub fn watch_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let path = path.as_ref().to_owned();
+        let keys = Arc::downgrade(&self.keys);
+        thread::spawn(move || poll_key_file(&keys, &path, interval))
+    }
+}
+
+fn poll_key_file(keys: &Weak<RwLock<Keys>>, path: &Path, interval: Duration) {
+    let mut last_modified = modified(path);
+    loop {
+        thread::sleep(interval);
+        let keyring = match keys.upgrade() {
+            Some(keys) => Keyring { keys: keys },
+            None => return,
+        };
+        let modified = modified(path);
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        match read_key_file(path) {
+            Ok(key) => {
+                if let Err(err) = keyring.replace(&key) {
+                    warn!("Not using new key from {:?}: {}", path, err);
+                }
+            }
+            Err(err) => warn!("Failed to read key {:?}: {}", path, err),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaced_key_is_still_accepted() {
+        let keyring = Keyring::new(&[1; 32]).unwrap();
+        let shared = keyring.clone();
+        keyring.replace(&[2; 32]).unwrap();
+        assert_eq!(shared.current(), vec![2; 32]);
+        assert_eq!(shared.accepted(), vec![vec![2; 32], vec![1; 32]]);
+        assert!(keyring.replace(b"weak").is_err());
+        assert_eq!(shared.current(), vec![2; 32]);
+    }
+}
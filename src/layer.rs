@@ -0,0 +1,442 @@
+//! A tower `Layer` for hyper and axum services.
+//!
+//! The `SessionLayer` finds and verifies tokens exactly like the nickel
+//! `SessionMiddleware`, so services using either can share sessions.
+
+use context::AuthContext;
+use error::SessionError;
+use http::header::{HeaderMap, HeaderName, HeaderValue, COOKIE, SET_COOKIE};
+use http::{Request, Response};
+use rustc_serialize::json::Json;
+use session_core::{self, RequestParts, SessionCore, TokenLocation,
+                   TokenOptions, TokenOutput, TokenSource};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A tower `Layer` for jwt-based sessions.
+///
+/// Each request passing through the layer gets a `SessionHandle` in
+/// its extensions, with the data of a valid token, if any, and methods
+/// for setting and clearing the token on the response.
+///
+/// ```ignore
+/// let app = Router::new()
+///     .route("/", get(home))
+///     .layer(SessionLayer::new(SessionCore::from_env("NICKEL_JWT_")?));
+///
+/// async fn home(Extension(session): Extension<SessionHandle>) -> String {
+///     match session.authorized_user() {
+///         Some(user) => format!("Hello, {}", user),
+///         None => "Hello, stranger".to_owned(),
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct SessionLayer {
+    core: Arc<SessionCore>,
+}
+
+impl SessionLayer {
+    /// Create a new instance.
+    pub fn new(core: SessionCore) -> SessionLayer {
+        SessionLayer { core: Arc::new(core) }
+    }
+}
+
+impl<S> Layer<S> for SessionLayer {
+    type Service = SessionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SessionService {
+            inner: inner,
+            core: self.core.clone(),
+        }
+    }
+}
+
+/// The service created by a `SessionLayer`.
+#[derive(Clone)]
+pub struct SessionService<S> {
+    inner: S,
+    core: Arc<SessionCore>,
+}
+
+impl<S, B> Service<Request<B>> for SessionService<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> S::Future {
+        let handle = SessionHandle::for_request(self.core.clone(), &req);
+        if let Some(user) = handle.authorized_user() {
+            info!("User {:?} is authorized for {}", user, req.uri().path());
+        }
+        req.extensions_mut().insert(handle);
+        self.inner.call(req)
+    }
+}
+
+/// The session of a request passing through a `SessionLayer`.
+///
+/// Get it from the request extensions, e.g. with the axum
+/// `Extension<SessionHandle>` extractor.
+#[derive(Clone)]
+pub struct SessionHandle {
+    core: Arc<SessionCore>,
+    context: Option<Arc<AuthContext>>,
+    found: Option<TokenLocation>,
+    chunk_counts: Arc<BTreeMap<String, usize>>,
+}
+
+impl SessionHandle {
+    fn for_request<B>(core: Arc<SessionCore>, req: &Request<B>) -> Self {
+        let mut source = HttpSource::new(req);
+        let mut chunk_counts = BTreeMap::new();
+        for location in core.locations() {
+            if let TokenLocation::Cookie(ref name) = *location {
+                chunk_counts.insert(
+                    name.clone(),
+                    session_core::cookie_chunk_count(&source, name),
+                );
+            }
+        }
+        let found = core.find_token(&mut source);
+        let context = found
+            .as_ref()
+            .and_then(|found| core.verify(&found.token).ok())
+            .map(Arc::new);
+        SessionHandle {
+            core: core,
+            context: context,
+            found: found.map(|found| found.location),
+            chunk_counts: Arc::new(chunk_counts),
+        }
+    }
+
+    /// Get all data of the valid token of the request, if any.
+    pub fn auth_context(&self) -> Option<&AuthContext> {
+        self.context.as_ref().map(|context| &**context)
+    }
+
+    /// Get the username of the valid token of the request, if any.
+    pub fn authorized_user(&self) -> Option<&str> {
+        self.auth_context()
+            .and_then(|context| context.registered.sub.as_ref())
+            .map(|sub| sub.as_str())
+    }
+
+    /// Get the custom claims of the valid token of the request, if any.
+    ///
+    /// As for the nickel middleware, None is returned if the token has
+    /// no custom claims.
+    pub fn valid_custom_claims(&self) -> Option<&BTreeMap<String, Json>> {
+        self.auth_context()
+            .map(|context| &context.custom_claims)
+            .filter(|claims| !claims.is_empty())
+    }
+
+    /// Create a token for the given username and set it on a response.
+    pub fn set_jwt_user<B>(
+        &self,
+        response: &mut Response<B>,
+        user: &str,
+    ) -> Result<(), SessionError> {
+        let options = TokenOptions {
+            subject: Some(user.to_owned()),
+            ..Default::default()
+        };
+        self.issue(response, options).map(|_| ())
+    }
+
+    /// Create a token with the given custom claims and set it on a
+    /// response.
+    pub fn set_jwt_custom_claims<B>(
+        &self,
+        response: &mut Response<B>,
+        claims: BTreeMap<String, Json>,
+    ) -> Result<(), SessionError> {
+        let options = TokenOptions {
+            claims: claims,
+            ..Default::default()
+        };
+        self.issue(response, options).map(|_| ())
+    }
+
+    /// Create a token with the given options and set it on a response.
+    ///
+    /// The token is also returned, for locations where the caller is
+    /// responsible for sending it.
+    pub fn issue<B>(
+        &self,
+        response: &mut Response<B>,
+        options: TokenOptions,
+    ) -> Result<String, SessionError> {
+        let location = self.core.location_for(self.found.as_ref());
+        let chunks = self.old_cookie_chunks(&location);
+        let issued = self.core.issue(options, &location, chunks)?;
+        apply_output(response, issued.output)?;
+        Ok(issued.token)
+    }
+
+    /// Clear the token on a response.
+    pub fn clear_jwt<B>(
+        &self,
+        response: &mut Response<B>,
+    ) -> Result<(), SessionError> {
+        let location = self.core.location_for(self.found.as_ref());
+        let chunks = self.old_cookie_chunks(&location);
+        apply_output(response, self.core.clear(&location, chunks))
+    }
+
+    fn old_cookie_chunks(&self, location: &TokenLocation) -> usize {
+        match *location {
+            TokenLocation::Cookie(ref name) => {
+                self.chunk_counts.get(name).cloned().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Token lookup in a http request.
+struct HttpSource<'r> {
+    headers: &'r HeaderMap,
+    parts: RequestParts,
+}
+
+impl<'r> HttpSource<'r> {
+    fn new<B>(req: &'r Request<B>) -> Self {
+        let mut parts = RequestParts::new();
+        for value in req.headers().get_all(COOKIE) {
+            if let Ok(value) = value.to_str() {
+                parts = parts.header("Cookie", value);
+            }
+        }
+        HttpSource {
+            headers: req.headers(),
+            parts: parts.query(req.uri().query().unwrap_or("")),
+        }
+    }
+}
+
+impl<'r> TokenSource for HttpSource<'r> {
+    fn cookie(&self, name: &str) -> Option<String> {
+        self.parts.cookie(name)
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    }
+
+    fn query_param(&mut self, name: &str) -> Option<String> {
+        self.parts.query_param(name)
+    }
+}
+
+/// Add the output of the `SessionCore` to a response.
+fn apply_output<B>(
+    response: &mut Response<B>,
+    output: TokenOutput,
+) -> Result<(), SessionError> {
+    match output {
+        TokenOutput::Cookies(cookies) => for cookie in cookies {
+            let value = header_value(cookie)?;
+            response.headers_mut().append(SET_COOKIE, value);
+        },
+        TokenOutput::Header(name, value) => {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| SessionError::InvalidHeader(name.clone()))?;
+            let value = header_value(value)?;
+            response.headers_mut().insert(name, value);
+        }
+        TokenOutput::Nothing => {}
+    }
+    Ok(())
+}
+
+fn header_value(value: String) -> Result<HeaderValue, SessionError> {
+    HeaderValue::from_str(&value)
+        .map_err(|_| SessionError::InvalidHeader(value.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cookie::Cookie;
+    use http::header::AUTHORIZATION;
+    use session_core::WriteLocation;
+    use std::future::{ready, Ready};
+    use std::sync::Mutex;
+
+    const KEY: &'static str = "A secret key for tests, at least 32 bytes";
+
+    /// An inner service that keeps the session handle of the request.
+    #[derive(Clone, Default)]
+    struct Stub {
+        handle: Arc<Mutex<Option<SessionHandle>>>,
+    }
+
+    impl Service<Request<()>> for Stub {
+        type Response = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<()>) -> Self::Future {
+            let handle = req.extensions().get::<SessionHandle>().cloned();
+            *self.handle.lock().unwrap() = handle;
+            ready(Ok(()))
+        }
+    }
+
+    /// Pass a request with the given headers through a `SessionService`
+    /// and get the session handle the inner service got.
+    fn handle(core: SessionCore, headers: &[(&str, &str)]) -> SessionHandle {
+        let stub = Stub::default();
+        let mut service = SessionLayer::new(core).layer(stub.clone());
+        let mut request = Request::builder().uri("/");
+        for &(name, value) in headers {
+            request = request.header(name, value);
+        }
+        let _ = service.call(request.body(()).unwrap());
+        let handle = stub.handle.lock().unwrap().take();
+        handle.expect("session handle")
+    }
+
+    fn token_for(core: &SessionCore, user: &str) -> String {
+        let options = TokenOptions {
+            subject: Some(user.to_owned()),
+            ..Default::default()
+        };
+        core.make_token(options).unwrap()
+    }
+
+    /// Get the set cookies and the names of removed cookies.
+    fn set_cookies(
+        response: &Response<()>,
+    ) -> (Vec<(String, String)>, Vec<String>) {
+        let (mut set, mut removed) = (Vec::new(), Vec::new());
+        for value in response.headers().get_all(SET_COOKIE) {
+            let cookie = Cookie::parse(value.to_str().unwrap()).unwrap();
+            if cookie.value().is_empty() {
+                removed.push(cookie.name().to_owned());
+            } else {
+                set.push((cookie.name().to_owned(), cookie.value().to_owned()));
+            }
+        }
+        (set, removed)
+    }
+
+    fn core() -> SessionCore {
+        SessionCore::new(KEY)
+            .using(TokenLocation::Cookie("jwt".to_owned()))
+            .also_using(TokenLocation::AuthorizationHeader)
+    }
+
+    #[test]
+    fn tokens_in_cookies_and_headers() {
+        let token = token_for(&core(), "carl");
+        let cookie = format!("jwt={}", token);
+        let session = handle(core(), &[("Cookie", &cookie)]);
+        assert_eq!(session.authorized_user(), Some("carl"));
+
+        let bearer = format!("Bearer {}", token);
+        let session = handle(core(), &[("Authorization", &bearer)]);
+        assert_eq!(session.authorized_user(), Some("carl"));
+
+        let session = handle(core(), &[("Cookie", "jwt=not.a.token")]);
+        assert_eq!(session.authorized_user(), None);
+        assert_eq!(handle(core(), &[]).authorized_user(), None);
+    }
+
+    #[test]
+    fn set_and_clear_cookies_with_chunks() {
+        let mut options = TokenOptions::default();
+        options
+            .claims
+            .insert("data".to_owned(), Json::String("x".repeat(5000)));
+        let location = TokenLocation::Cookie("jwt".to_owned());
+        let issued = core().issue(options, &location, 0).unwrap();
+        let cookies = match issued.output {
+            TokenOutput::Cookies(cookies) => cookies
+                .iter()
+                .map(|c| Cookie::parse(c.as_str()).unwrap())
+                .filter(|c| !c.value().is_empty())
+                .map(|c| format!("{}={}", c.name(), c.value()))
+                .collect::<Vec<_>>(),
+            other => panic!("Expected cookies, got {:?}", other),
+        };
+        assert_eq!(cookies.len(), 2);
+        let session = handle(core(), &[("Cookie", &cookies.join("; "))]);
+        assert!(session.valid_custom_claims().is_some());
+
+        let mut response = Response::new(());
+        session.set_jwt_user(&mut response, "carl").unwrap();
+        let (set, removed) = set_cookies(&response);
+        assert_eq!(set.len(), 1);
+        assert_eq!(set[0].0, "jwt");
+        assert_eq!(removed, ["jwt.0", "jwt.1"]);
+        let cookie = format!("jwt={}", set[0].1);
+        let session = handle(core(), &[("Cookie", &cookie)]);
+        assert_eq!(session.authorized_user(), Some("carl"));
+
+        let mut response = Response::new(());
+        let chunked = handle(core(), &[("Cookie", &cookies.join("; "))]);
+        chunked.clear_jwt(&mut response).unwrap();
+        let (set, removed) = set_cookies(&response);
+        assert!(set.is_empty());
+        assert_eq!(removed, ["jwt", "jwt.0", "jwt.1"]);
+    }
+
+    #[test]
+    fn set_and_clear_headers() {
+        let core = || core().write_location(WriteLocation::Mirror);
+        let bearer = format!("Bearer {}", token_for(&core(), "carl"));
+        let session = handle(core(), &[("Authorization", &bearer)]);
+
+        let mut response = Response::new(());
+        session.set_jwt_user(&mut response, "dave").unwrap();
+        assert!(response.headers().get(SET_COOKIE).is_none());
+        let bearer = response.headers()[AUTHORIZATION].to_str().unwrap();
+        let session = handle(core(), &[("Authorization", bearer)]);
+        assert_eq!(session.authorized_user(), Some("dave"));
+
+        let mut response = Response::new(());
+        session.clear_jwt(&mut response).unwrap();
+        assert_eq!(response.headers()[AUTHORIZATION], "Bearer ");
+    }
+
+    #[test]
+    fn invalid_headers_are_errors() {
+        let mut response = Response::new(());
+        let output = TokenOutput::Header("Bad name".into(), "x".into());
+        match apply_output(&mut response, output) {
+            Err(SessionError::InvalidHeader(name)) => {
+                assert_eq!(name, "Bad name")
+            }
+            other => panic!("Expected invalid header, got {:?}", other),
+        }
+        let output = TokenOutput::Header("X-Token".into(), "a\nb".into());
+        match apply_output(&mut response, output) {
+            Err(SessionError::InvalidHeader(_)) => (),
+            other => panic!("Expected invalid header, got {:?}", other),
+        }
+        assert!(response.headers().is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+//! Counters for token verification outcomes.
+
+use session_core::Rejection;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Counters for tokens seen, verified, rejected, issued and cleared.
+///
+/// All clones of a `Metrics` share the same counters, so a clone can
+/// be kept for reading while the `SessionCore` or `SessionMiddleware`
+/// updates them.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    seen: AtomicUsize,
+    verified: AtomicUsize,
+    malformed: AtomicUsize,
+    bad_signature: AtomicUsize,
+    not_yet_valid: AtomicUsize,
+    expired: AtomicUsize,
+    wrong_audience: AtomicUsize,
+    revoked: AtomicUsize,
+    issued: AtomicUsize,
+    cleared: AtomicUsize,
+}
+
+/// The values of the counters of a `Metrics` at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Tokens found in requests.
+    pub seen: usize,
+    /// Tokens that were valid.
+    pub verified: usize,
+    /// Tokens that could not be parsed.
+    pub malformed: usize,
+    /// Tokens with a bad signature.
+    pub bad_signature: usize,
+    /// Tokens that were not yet valid.
+    pub not_yet_valid: usize,
+    /// Tokens that were expired.
+    pub expired: usize,
+    /// Tokens for another audience.
+    pub wrong_audience: usize,
+    /// Tokens that were revoked.
+    pub revoked: usize,
+    /// New tokens issued.
+    pub issued: usize,
+    /// Tokens cleared.
+    pub cleared: usize,
+}
+
+impl Metrics {
+    /// Create a new instance, with all counters at zero.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Get the current values of all counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let c = &self.counters;
+        let get = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            seen: get(&c.seen),
+            verified: get(&c.verified),
+            malformed: get(&c.malformed),
+            bad_signature: get(&c.bad_signature),
+            not_yet_valid: get(&c.not_yet_valid),
+            expired: get(&c.expired),
+            wrong_audience: get(&c.wrong_audience),
+            revoked: get(&c.revoked),
+            issued: get(&c.issued),
+            cleared: get(&c.cleared),
+        }
+    }
+
+    /// Get the counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let s = self.snapshot();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP jwt_session_tokens_seen_total Tokens found in requests.\n\
+             # TYPE jwt_session_tokens_seen_total counter\n\
+             jwt_session_tokens_seen_total {}",
+            s.seen
+        );
+        let _ = writeln!(
+            out,
+            "# HELP jwt_session_tokens_total Verification outcomes.\n\
+             # TYPE jwt_session_tokens_total counter"
+        );
+        for &(outcome, value) in &[
+            ("verified", s.verified),
+            ("malformed", s.malformed),
+            ("bad_signature", s.bad_signature),
+            ("not_yet_valid", s.not_yet_valid),
+            ("expired", s.expired),
+            ("wrong_audience", s.wrong_audience),
+            ("revoked", s.revoked),
+        ] {
+            let _ = writeln!(
+                out,
+                "jwt_session_tokens_total{{outcome=\"{}\"}} {}",
+                outcome,
+                value
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP jwt_session_tokens_issued_total New tokens issued.\n\
+             # TYPE jwt_session_tokens_issued_total counter\n\
+             jwt_session_tokens_issued_total {}\n\
+             # HELP jwt_session_tokens_cleared_total Tokens cleared.\n\
+             # TYPE jwt_session_tokens_cleared_total counter\n\
+             jwt_session_tokens_cleared_total {}",
+            s.issued,
+            s.cleared
+        );
+        out
+    }
+
+    pub(crate) fn verified(&self) {
+        self.counters.seen.fetch_add(1, Ordering::Relaxed);
+        self.counters.verified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rejected(&self, rejection: Rejection) {
+        let c = &self.counters;
+        c.seen.fetch_add(1, Ordering::Relaxed);
+        match rejection {
+            Rejection::Malformed => &c.malformed,
+            Rejection::BadSignature => &c.bad_signature,
+            Rejection::NotYetValid => &c.not_yet_valid,
+            Rejection::Expired => &c.expired,
+            Rejection::WrongAudience => &c.wrong_audience,
+            Rejection::Revoked => &c.revoked,
+        }.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn issued(&self) {
+        self.counters.issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cleared(&self) {
+        self.counters.cleared.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_outcomes() {
+        let metrics = Metrics::new();
+        metrics.clone().verified();
+        metrics.rejected(Rejection::Expired);
+        metrics.rejected(Rejection::Expired);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.seen, 3);
+        assert_eq!(snapshot.verified, 1);
+        assert_eq!(snapshot.expired, 2);
+        assert!(metrics
+            .prometheus()
+            .contains("jwt_session_tokens_total{outcome=\"expired\"} 2\n"));
+    }
+}
@@ -0,0 +1,1585 @@
+//! The nickel middleware, an adapter around the `SessionCore`.
+
+use audit::{AuditEvent, AuditKind, AuditSink};
+use cache::TokenCache;
+use claims::{self, ClaimsError};
+use config::ConfigError;
+use context::AuthContext;
+use csrf::{self, CsrfProtection};
+use error::SessionError;
+use hyper::header::{self, SetCookie};
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use issue::TokenBuilder;
+use jwe::Encryption;
+use nested::NestedTokens;
+use key::KeyError;
+use metrics::Metrics;
+use nickel::{Continue, FormBody, Middleware, MiddlewareResult, NickelError,
+             QueryString, Request, Response};
+use nickel::status::StatusCode;
+use paths::PathFilter;
+use plugin::Extensible;
+use redact::Redaction;
+use revocation::RevocationCheck;
+use rustc_serialize::json::Json;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use session_core::{self, Rejected, SessionCore, TokenLocation,
+                   TokenOptions, TokenOutput, TokenSource, WriteLocation};
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use time::Duration;
+use typemap::{Key, TypeMap};
+use user::{CurrentUser, LoadUser, UserLoader};
+
+/// The middleware itself.
+///
+/// The configuration is shared through an `Arc`, so the clone kept on
+/// each response, for setting and clearing tokens, is cheap.
+#[derive(Clone)]
+pub struct SessionMiddleware {
+    config: Arc<Config>,
+}
+
+/// The configuration of a `SessionMiddleware`.
+#[derive(Clone)]
+struct Config {
+    /// The token handling.
+    core: SessionCore,
+    /// Csrf protection for cookie-based sessions, if enabled.
+    csrf: Option<CsrfProtection>,
+    /// Hook for loading the user of a valid token, if any.
+    user_loader: Option<Arc<dyn LoadUser>>,
+    /// Receiver of audit events, if any.
+    audit: Option<Arc<dyn AuditSink>>,
+    /// Cache of verified tokens, if enabled.
+    cache: Option<TokenCache>,
+    /// Verify tokens on first access rather than in `invoke`.
+    lazy: bool,
+    /// The request paths to handle.
+    paths: PathFilter,
+}
+
+impl SessionMiddleware {
+    /// Create a new instance.
+    ///
+    /// The `server_key` is used for signing and validating the jwt token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is shorter than `key::MIN_KEY_LENGTH` bytes.
+    /// Use `try_new()` to handle a weak key as an error.
+    pub fn new(server_key: &str) -> SessionMiddleware {
+        SessionMiddleware::from_core(SessionCore::new(server_key))
+    }
+
+    /// Create a new instance, with raw key bytes.
+    ///
+    /// See `SessionCore::try_new()`.
+    pub fn try_new(server_key: &[u8]) -> Result<SessionMiddleware, KeyError> {
+        SessionCore::try_new(server_key).map(SessionMiddleware::from_core)
+    }
+
+    /// Create a new instance from a `SessionCore`.
+    ///
+    /// This makes it easy to share the configuration with services
+    /// that use the `SessionCore` directly.
+    pub fn from_core(core: SessionCore) -> SessionMiddleware {
+        SessionMiddleware {
+            config: Arc::new(Config {
+                core: core,
+                csrf: None,
+                user_loader: None,
+                audit: None,
+                cache: None,
+                lazy: false,
+                paths: PathFilter::default(),
+            }),
+        }
+    }
+
+    /// Create a new instance from `NICKEL_JWT_*` environment variables.
+    ///
+    /// See `SessionCore::from_env()`.
+    pub fn from_env() -> Result<SessionMiddleware, ConfigError> {
+        SessionCore::from_env("NICKEL_JWT_").map(SessionMiddleware::from_core)
+    }
+
+    /// Create a new instance from a section of a toml file.
+    ///
+    /// See `SessionCore::from_toml_file()`.
+    pub fn from_toml_file<P: AsRef<Path>>(
+        path: P,
+        section: &str,
+    ) -> Result<SessionMiddleware, ConfigError> {
+        SessionCore::from_toml_file(path, section)
+            .map(SessionMiddleware::from_core)
+    }
+
+    /// Get the `SessionCore` of this middleware.
+    pub fn core(&self) -> &SessionCore {
+        &self.config.core
+    }
+
+    /// Set a value for the iss (issuer) jwt claim.
+    ///
+    /// The default is to not set an issuer.
+    pub fn issuer(self, issuer: &str) -> Self {
+        self.map_core(|core| core.issuer(issuer))
+    }
+
+    /// Set how long a token should be valid after creation (in seconds).
+    ///
+    /// The default is 24 hours.
+    pub fn expiration_time(self, expiration_time: Duration) -> Self {
+        self.map_core(|core| core.expiration_time(expiration_time))
+    }
+
+    /// Set where the token should be stored.
+    ///
+    /// The default is `TokenLocation::Cookie("jwt")`. Alternatively,
+    /// the token can be set in the `Authorization: Bearer` header.
+    ///
+    /// This replaces any previously configured locations.
+    pub fn using(self, location: TokenLocation) -> Self {
+        self.map_core(|core| core.using(location))
+    }
+
+    /// Also look for the token in another location.
+    ///
+    /// Locations are searched in the order they are added, and the
+    /// first token found is used.  E.g. to serve both browsers and
+    /// api clients:
+    ///
+    /// ```ignore
+    /// SessionMiddleware::new("My very secret key, at least 32 bytes")
+    ///     .using(TokenLocation::Cookie("jwt".to_owned()))
+    ///     .also_using(TokenLocation::AuthorizationHeader)
+    ///     .write_location(WriteLocation::Mirror)
+    /// ```
+    pub fn also_using(self, location: TokenLocation) -> Self {
+        self.map_core(|core| core.also_using(location))
+    }
+
+    /// Set where new tokens are put when more than one location is used.
+    ///
+    /// The default is `WriteLocation::First`.
+    pub fn write_location(self, write_location: WriteLocation) -> Self {
+        self.map_core(|core| core.write_location(write_location))
+    }
+
+    /// Set what claim values to show in logs.
+    ///
+    /// Tokens are always logged as a fingerprint.  The default is to
+    /// mask all custom claim values.
+    pub fn redact_logs(self, redaction: Redaction) -> Self {
+        self.map_core(|core| core.redact_logs(redaction))
+    }
+
+    /// Encrypt new tokens, so their claims are not readable by clients.
+    ///
+    /// See `SessionCore::encrypt_tokens()`.
+    pub fn encrypt_tokens(self, encryption: Encryption) -> Self {
+        self.map_core(|core| core.encrypt_tokens(encryption))
+    }
+
+    /// Set the keys for nested tokens, shared with partner services.
+    ///
+    /// See `SessionCore::nested_tokens()`.
+    pub fn nested_tokens(self, nested: NestedTokens) -> Self {
+        self.map_core(|core| core.nested_tokens(nested))
+    }
+
+    /// Set a hook for rejecting revoked tokens.
+    ///
+    /// See `SessionCore::revocation_check()`.
+    pub fn revocation_check<R: RevocationCheck>(self, check: R) -> Self {
+        self.map_core(|core| core.revocation_check(check))
+    }
+
+    /// Cache up to `capacity` verified tokens.
+    ///
+    /// When a cached token is sent again, its signature is not checked
+    /// and its claims are not parsed again, which helps with api
+    /// clients that send the same bearer token with every request.
+    /// The expiration time and the `RevocationCheck` are still checked
+    /// for every request, and cached tokens are forgotten when the
+    /// server key is replaced.
+    ///
+    /// The default is to not cache tokens.
+    pub fn cache_verified_tokens(mut self, capacity: usize) -> Self {
+        Arc::make_mut(&mut self.config).cache =
+            Some(TokenCache::new(capacity));
+        self
+    }
+
+    /// Verify the token of a request only when it is needed.
+    ///
+    /// In lazy mode, the token is found in `invoke`, but not verified
+    /// until a handler first asks for it, e.g. by `authorized_user()`,
+    /// `valid_custom_claims()` or `auth_context()`.  The result is
+    /// remembered for the rest of the request.  This saves the work
+    /// of verifying tokens for e.g. static files, that are served the
+    /// same to everyone.
+    ///
+    /// A state-changing request with a token in a cookie is still
+    /// verified in `invoke` if csrf protection is enabled.  Tokens
+    /// that are never verified are not counted in the metrics, and
+    /// audit events for logouts have no subject if the token was
+    /// not verified before the logout.
+    ///
+    /// The default is to verify tokens in `invoke`.
+    pub fn lazy_verification(mut self, lazy: bool) -> Self {
+        Arc::make_mut(&mut self.config).lazy = lazy;
+        self
+    }
+
+    /// Only handle requests for paths matching a pattern.
+    ///
+    /// A `*` in the pattern matches any sequence of characters,
+    /// including `/`, so `/api/*` matches everything below `/api/`.  A
+    /// pattern without `*` matches only that exact path.  When called
+    /// more than once, paths matching any of the patterns are handled.
+    ///
+    /// Requests for other paths are passed on untouched, without
+    /// looking for a token, so handlers for them can't use the
+    /// session or set a token.  The default is to handle all paths.
+    pub fn include_path(mut self, pattern: &str) -> Self {
+        Arc::make_mut(&mut self.config).paths.include(pattern);
+        self
+    }
+
+    /// Don't handle requests for paths matching a pattern.
+    ///
+    /// Patterns are as for `include_path()`, and exclusions apply to
+    /// included paths as well.  E.g. to skip static files and health
+    /// checks:
+    ///
+    /// ```ignore
+    /// SessionMiddleware::new("My very secret key, at least 32 bytes")
+    ///     .exclude_path("/static/*")
+    ///     .exclude_path("/health")
+    /// ```
+    pub fn exclude_path(mut self, pattern: &str) -> Self {
+        Arc::make_mut(&mut self.config).paths.exclude(pattern);
+        self
+    }
+
+    /// Enable csrf protection for cookie-based sessions.
+    ///
+    /// When enabled, a POST, PUT, PATCH or DELETE request with a
+    /// valid token in a cookie is rejected with `403 Forbidden`
+    /// unless it also has a matching csrf token in a header or form
+    /// field.  The csrf token for the current session is available
+    /// through the `csrf_token()` request method, e.g. to put in a
+    /// hidden form field in a template.
+    ///
+    /// The default is no csrf protection.
+    pub fn csrf_protection(mut self, csrf: CsrfProtection) -> Self {
+        Arc::make_mut(&mut self.config).csrf = Some(csrf);
+        self
+    }
+
+    /// Set a hook for loading the user of a valid token.
+    ///
+    /// The loader is called with the subject of each valid token, and
+    /// the loaded user is available to handlers through the
+    /// `current_user()` request method.
+    ///
+    /// The default is to not load any user.
+    pub fn user_loader<L: UserLoader>(mut self, loader: L) -> Self {
+        Arc::make_mut(&mut self.config).user_loader = Some(Arc::new(loader));
+        self
+    }
+
+    /// Set a receiver for audit events.
+    ///
+    /// The sink is called when a token is set or cleared on a
+    /// response, and when the token of a request is rejected.
+    ///
+    /// The default is to not record any audit events.
+    pub fn audit_sink<S: AuditSink + 'static>(mut self, sink: S) -> Self {
+        Arc::make_mut(&mut self.config).audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// Change the `SessionCore` of this middleware.
+    fn map_core<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(SessionCore) -> SessionCore,
+    {
+        let config = Arc::make_mut(&mut self.config);
+        config.core = f(config.core.clone());
+        self
+    }
+
+    /// Verify a token, using the cache if enabled.
+    fn verify(&self, token: &str) -> Result<AuthContext, Rejected> {
+        let core = &self.config.core;
+        let cache = match self.config.cache {
+            Some(ref cache) => cache,
+            None => return core.verify_detailed(token),
+        };
+        let generation = core.keyring().generation();
+        match cache.get(token, generation) {
+            Some(context) => {
+                let result = core.reverify(context);
+                if result.is_err() {
+                    cache.remove(token);
+                }
+                result
+            }
+            None => {
+                let result = core.verify_detailed(token);
+                if let Ok(ref context) = result {
+                    cache.insert(context, generation);
+                }
+                result
+            }
+        }
+    }
+
+    /// Record an audit event for a rejected token.
+    ///
+    /// If the token is authentic, e.g. just expired, the event has its
+    /// subject and jti.
+    fn audit_rejected(&self, rejected: Rejected, request: &AuditRequest) {
+        let kind = AuditKind::Rejected(rejected.rejection);
+        match rejected.context {
+            Some(context) => {
+                let request = AuditRequest {
+                    subject: context.registered.sub,
+                    jti: context.registered.jti,
+                    ..request.clone()
+                };
+                self.audit(kind, &request);
+            }
+            None => self.audit(kind, request),
+        }
+    }
+
+    /// Record an audit event, if there is an audit sink.
+    fn audit(&self, kind: AuditKind, request: &AuditRequest) {
+        if let Some(ref sink) = self.config.audit {
+            sink.record(&AuditEvent {
+                kind: kind,
+                time: ::current_numeric_date(),
+                subject: request.subject.clone(),
+                jti: request.jti.clone(),
+                remote_addr: request.remote_addr.clone(),
+                uri: request.uri.clone(),
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    authorized_user: String,
+}
+
+#[derive(Debug)]
+struct CustomSession {
+    claims: BTreeMap<String, Json>,
+}
+
+#[derive(Debug)]
+struct CsrfToken {
+    token: String,
+}
+
+impl Key for SessionMiddleware {
+    type Value = SessionMiddleware;
+}
+impl Key for Session {
+    type Value = Session;
+}
+impl Key for CustomSession {
+    type Value = CustomSession;
+}
+impl Key for CsrfToken {
+    type Value = CsrfToken;
+}
+impl Key for AuthContext {
+    type Value = AuthContext;
+}
+
+/// The number of jwt cookie chunks sent with the request, by the
+/// index of the location in the `SessionCore`.
+///
+/// Kept on the response, so stale chunks can be removed when the
+/// token is replaced or cleared.  Only added if the client has any
+/// chunks.
+#[derive(Debug)]
+struct CookieChunks {
+    counts: Vec<usize>,
+}
+
+/// The index of the location in the `SessionCore` where the token of
+/// the request was found.
+///
+/// Kept on the response, for `WriteLocation::Mirror`.
+#[derive(Debug)]
+struct TokenFound {
+    index: usize,
+}
+
+/// The last token created for a response.
+#[derive(Debug)]
+struct IssuedToken {
+    token: String,
+    expiration: Duration,
+}
+
+/// Request data for audit events on the response.
+#[derive(Clone, Debug)]
+struct AuditRequest {
+    remote_addr: String,
+    uri: String,
+    subject: Option<String>,
+    jti: Option<String>,
+}
+
+impl Key for CookieChunks {
+    type Value = CookieChunks;
+}
+impl Key for IssuedToken {
+    type Value = IssuedToken;
+}
+impl Key for AuditRequest {
+    type Value = AuditRequest;
+}
+impl Key for TokenFound {
+    type Value = TokenFound;
+}
+
+/// A token that is verified on first access, in lazy mode.
+struct LazyToken {
+    token: String,
+    middleware: SessionMiddleware,
+    /// True if the token is in a cookie and csrf protection is enabled.
+    csrf: bool,
+    /// Request data for audit events, if there is an audit sink.
+    audit: Option<AuditRequest>,
+    verified: OnceCell<Option<Verified>>,
+}
+
+/// The result of a lazy verification.
+struct Verified {
+    context: AuthContext,
+    /// The loaded user, if any.
+    user: TypeMap,
+}
+
+impl Key for LazyToken {
+    type Value = LazyToken;
+}
+
+impl LazyToken {
+    /// Verify the token, if not already done.
+    fn get(&self) -> Option<&Verified> {
+        self.verified.get_or_init(|| self.verify()).as_ref()
+    }
+
+    fn verify(&self) -> Option<Verified> {
+        let context = match self.middleware.verify(&self.token) {
+            Ok(context) => context,
+            Err(rejected) => {
+                if let Some(ref request) = self.audit {
+                    self.middleware.audit_rejected(rejected, request);
+                }
+                return None;
+            }
+        };
+        debug!("Lazily verified token for {:?}", context.registered.sub);
+        let mut user = TypeMap::new();
+        let loader = self.middleware.config.user_loader.as_ref();
+        if let (Some(loader), Some(sub)) = (loader, &context.registered.sub) {
+            loader.load_into(sub, &context.custom_claims, &mut user);
+        }
+        Some(Verified {
+            context: context,
+            user: user,
+        })
+    }
+}
+
+/// A nickel request as a `TokenSource`.
+struct NickelSource<'r, 'mw: 'r, 'conn: 'r, D: 'mw> {
+    req: &'r mut Request<'mw, 'conn, D>,
+    cookies: BTreeMap<String, String>,
+}
+
+impl<'r, 'mw, 'conn, D> NickelSource<'r, 'mw, 'conn, D> {
+    fn new(req: &'r mut Request<'mw, 'conn, D>) -> Self {
+        let mut cookies = BTreeMap::new();
+        if let Some(header) = req.origin.headers.get::<header::Cookie>() {
+            for cookie in header.iter() {
+                if let Ok(cookie) = ::cookie::Cookie::parse(cookie.to_string())
+                {
+                    cookies.insert(
+                        cookie.name().to_string(),
+                        cookie.value().to_string(),
+                    );
+                }
+            }
+        }
+        NickelSource {
+            req: req,
+            cookies: cookies,
+        }
+    }
+}
+
+impl<'r, 'mw, 'conn, D> TokenSource for NickelSource<'r, 'mw, 'conn, D> {
+    fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies.get(name).cloned()
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.req
+            .origin
+            .headers
+            .get_raw(name)
+            .and_then(|values| values.first())
+            .and_then(|v| String::from_utf8(v.clone()).ok())
+    }
+
+    fn query_param(&mut self, name: &str) -> Option<String> {
+        self.req.query().get(name).map(|t| t.to_owned())
+    }
+}
+
+impl<D> Middleware<D> for SessionMiddleware {
+    fn invoke<'mw, 'conn>(
+        &self,
+        req: &mut Request<'mw, 'conn, D>,
+        mut res: Response<'mw, D>,
+    ) -> MiddlewareResult<'mw, D> {
+        let path = request_path(&req.origin.uri);
+        if !path.map_or(false, |path| self.config.paths.selects(path)) {
+            return Ok(Continue(res));
+        }
+        res.extensions_mut().insert::<SessionMiddleware>((*self).clone());
+        if self.config.audit.is_some() {
+            res.extensions_mut().insert::<AuditRequest>(AuditRequest {
+                remote_addr: req.origin.remote_addr.to_string(),
+                uri: logged_path(&req.origin.uri).to_owned(),
+                subject: None,
+                jti: None,
+            });
+        }
+
+        let found = {
+            let mut source = NickelSource::new(req);
+            let locations = self.config.core.locations();
+            let chunk_count = |location: &TokenLocation| match *location {
+                TokenLocation::Cookie(ref name) => {
+                    session_core::cookie_chunk_count(&source, name)
+                }
+                _ => 0,
+            };
+            if locations.iter().any(|location| chunk_count(location) > 0) {
+                let counts = locations.iter().map(chunk_count).collect();
+                res.extensions_mut()
+                    .insert::<CookieChunks>(CookieChunks { counts: counts });
+            }
+            self.config.core.find_token(&mut source)
+        };
+        let found = match found {
+            Some(found) => found,
+            None => return Ok(Continue(res)),
+        };
+        let locations = self.config.core.locations();
+        if let Some(index) = locations.iter().position(|l| *l == found.location)
+        {
+            res.extensions_mut()
+                .insert::<TokenFound>(TokenFound { index: index });
+        }
+        if self.config.lazy {
+            let csrf = self.config.csrf.is_some() &&
+                csrf::applies_to(&found.location);
+            let method = req.origin.method.to_string();
+            if !(csrf && csrf::is_state_changing(&method)) {
+                req.extensions_mut().insert::<LazyToken>(LazyToken {
+                    token: found.token,
+                    middleware: self.clone(),
+                    csrf: csrf,
+                    audit: res.extensions().get::<AuditRequest>().cloned(),
+                    verified: OnceCell::new(),
+                });
+                return Ok(Continue(res));
+            }
+        }
+        let context = match self.verify(&found.token) {
+            Ok(context) => context,
+            Err(rejected) => {
+                if let Some(request) = res.extensions().get::<AuditRequest>() {
+                    self.audit_rejected(rejected, request);
+                }
+                return Ok(Continue(res));
+            }
+        };
+        if let Some(request) = res.extensions_mut().get_mut::<AuditRequest>() {
+            request.subject = context.registered.sub.clone();
+            request.jti = context.registered.jti.clone();
+        }
+
+        let csrf = match self.config.csrf {
+            Some(ref csrf) if csrf::applies_to(&found.location) => Some(csrf),
+            _ => None,
+        };
+        if let Some(csrf) = csrf {
+            let method = req.origin.method.to_string();
+            if context.registered.jti.is_none() &&
+                csrf::is_state_changing(&method)
+            {
+                // There is no csrf token for a session without a jti,
+                // e.g. from before csrf protection, so the request is
+                // handled without the session rather than refused.
+                info!(
+                    "Ignoring session without jti for {} on {}",
+                    req.origin.remote_addr,
+                    logged_path(&req.origin.uri)
+                );
+                return Ok(Continue(res));
+            }
+            let submitted = if csrf::is_state_changing(&method) {
+                submitted_csrf_token(req, csrf)
+            } else {
+                None
+            };
+            let valid = self.config.core.check_csrf(
+                &method,
+                &context,
+                submitted.as_ref().map(|s| s.as_str()),
+            );
+            if !valid {
+                warn!(
+                    "Bad csrf token for {} on {}",
+                    req.origin.remote_addr,
+                    logged_path(&req.origin.uri)
+                );
+                return Err(NickelError::new(
+                    res,
+                    "Missing or bad csrf token",
+                    StatusCode::Forbidden,
+                ));
+            }
+            let expected = context
+                .registered
+                .jti
+                .as_ref()
+                .map(|jti| self.config.core.csrf_token(jti));
+            if let Some(token) = expected {
+                req.extensions_mut()
+                    .insert::<CsrfToken>(CsrfToken { token: token });
+            }
+        }
+        if let Some(ref user) = context.registered.sub {
+            info!(
+                "User {:?} is authorized for {} on {}",
+                user,
+                req.origin.remote_addr,
+                logged_path(&req.origin.uri)
+            );
+            if let Some(ref loader) = self.config.user_loader {
+                loader.load_into(
+                    user,
+                    &context.custom_claims,
+                    req.extensions_mut(),
+                );
+            }
+            req.extensions_mut().insert::<Session>(Session {
+                authorized_user: user.clone(),
+            });
+        }
+        let custom_claims = &context.custom_claims;
+        if !custom_claims.is_empty() {
+            info!(
+                "Custom claims {} are valid for {} on {}",
+                self.config.core.redaction().claims(custom_claims),
+                req.origin.remote_addr,
+                logged_path(&req.origin.uri)
+            );
+            req.extensions_mut().insert::<CustomSession>(CustomSession {
+                claims: custom_claims.clone(),
+            });
+        }
+        req.extensions_mut().insert::<AuthContext>(context);
+
+        Ok(Continue(res))
+    }
+}
+
+/// A middleware serving session metrics for Prometheus.
+///
+/// Get requests for the configured path are answered with the
+/// counters of a `Metrics` in the Prometheus text exposition format:
+///
+/// ```ignore
+/// let sessions = SessionMiddleware::new(key);
+/// let metrics = sessions.core().metrics().clone();
+/// server.utilize(sessions);
+/// server.utilize(MetricsEndpoint::new("/metrics", metrics));
+/// ```
+///
+/// The endpoint is available without authorization, so consider
+/// serving it on an internal address only.
+pub struct MetricsEndpoint {
+    path: String,
+    metrics: Metrics,
+}
+
+impl MetricsEndpoint {
+    /// Create a new instance, serving `metrics` on `path`.
+    pub fn new(path: &str, metrics: Metrics) -> MetricsEndpoint {
+        MetricsEndpoint {
+            path: path.to_owned(),
+            metrics: metrics,
+        }
+    }
+}
+
+impl<D> Middleware<D> for MetricsEndpoint {
+    fn invoke<'mw, 'conn>(
+        &self,
+        req: &mut Request<'mw, 'conn, D>,
+        mut res: Response<'mw, D>,
+    ) -> MiddlewareResult<'mw, D> {
+        let is_metrics = req.origin.method == Method::Get &&
+            request_path(&req.origin.uri) == Some(self.path.as_str());
+        if !is_metrics {
+            return Ok(Continue(res));
+        }
+        res.headers_mut().set_raw(
+            "Content-Type",
+            vec![b"text/plain; version=0.0.4".to_vec()],
+        );
+        res.send(self.metrics.prometheus())
+    }
+}
+
+/// Extension trait for the request.
+///
+/// This trait is implemented for `nickel::Request`.
+/// Use this trait to be able to get the token info for a nickel
+/// request.
+pub trait SessionRequestExtensions {
+    /// Check if there is a valid token with an authorized user.
+    ///
+    /// If there is a valid token that has a username, Some(username)
+    /// is returned, otherwise, None is returned.
+    fn authorized_user(&self) -> Option<String>;
+
+    /// Check if there is a valid token with custom claims data.
+    ///
+    /// If there is a valid token that has custom claims set,
+    /// Some(&BTreeMap<String, Json>) is returned, otherwise, None is returned.
+    fn valid_custom_claims(&self) -> Option<&BTreeMap<String, Json>>;
+
+    /// Get the custom claims of a valid token as a typed value.
+    ///
+    /// If there is a valid token that has custom claims set, they are
+    /// deserialized to a `T`, otherwise, None is returned.
+    /// The claims may not match `T`, e.g. if the token was created by
+    /// an older version of the application, so the result of the
+    /// deserialization is returned for the caller to handle.
+    fn claims<T: DeserializeOwned>(&self) -> Option<Result<T, ClaimsError>>;
+
+    /// Get the user loaded for a valid token.
+    ///
+    /// This requires a `UserLoader` with `User = U` to be set on the
+    /// `SessionMiddleware`.  If there is a valid token and the loader
+    /// found a user for its subject, Some(&user) is returned,
+    /// otherwise, None is returned.
+    fn current_user<U: 'static>(&self) -> Option<&U>;
+
+    /// Get all data of a valid token.
+    ///
+    /// If there is a valid token, Some(&AuthContext) with its header,
+    /// registered and custom claims is returned, otherwise, None is
+    /// returned.
+    fn auth_context(&self) -> Option<&AuthContext>;
+
+    /// Get the csrf token for the current session.
+    ///
+    /// This is only available if csrf protection is enabled on the
+    /// `SessionMiddleware` and there is a valid token in a cookie.
+    /// Submit it in the configured header or form field with any
+    /// state-changing request.
+    fn csrf_token(&self) -> Option<String>;
+}
+
+/// Extension trait for the response.
+///
+/// This trait is implemented for `nickel::Response`.
+/// A jwt cookie or an Authorization: Bearer header signed with the
+/// secret key will be added to the response.
+/// It is the responsibility of the caller to actually validate
+/// the user (e.g. by password, or by CAS or some other mechanism)
+/// before calling this method.
+/// The token will be valid for the expiration_time specified on
+/// the `SessionMiddleware` from the current time.
+/// Use this trait to be able to set and clear a jwt token on a nickel
+/// response.
+/// The methods that set or clear the token return an error if there
+/// is no `SessionMiddleware` on the response or the token can't be
+/// created, so a misconfigured route fails rather than silently
+/// leaving the user without a token.
+pub trait SessionResponseExtensions {
+    /// Set the user. Convenience method for cases with only a username and
+    /// no custom claims.
+    fn set_jwt_user(&mut self, user: &str) -> Result<(), SessionError>;
+
+    /// Set the custom jwt claims data. Convenience method for cases with only
+    /// custom claims and without a username.
+    fn set_jwt_custom_claims(
+        &mut self,
+        claims: BTreeMap<String, Json>,
+    ) -> Result<(), SessionError>;
+
+    /// Set both the user and custom claims.
+    fn set_jwt_user_and_custom_claims(
+        &mut self,
+        user: &str,
+        claims: BTreeMap<String, Json>,
+    ) -> Result<(), SessionError>;
+
+    /// Set the custom jwt claims from a typed value.
+    ///
+    /// The value must serialize to a json object, the fields of which
+    /// become the custom claims.  Use `claims()` on the request to get
+    /// the value back.
+    fn set_jwt_claims<T: Serialize>(
+        &mut self,
+        claims: &T,
+    ) -> Result<(), SessionError>;
+
+    /// Set both the user and custom claims from a typed value.
+    fn set_jwt_user_and_claims<T: Serialize>(
+        &mut self,
+        user: &str,
+        claims: &T,
+    ) -> Result<(), SessionError>;
+
+    /// Start building a token with other options than the defaults
+    /// of the `SessionMiddleware`.
+    ///
+    /// Call `set()` on the returned builder to set the token on the
+    /// response.
+    fn issue_jwt<'r>(&'r mut self) -> TokenBuilder<'r, Self>
+    where
+        Self: Sized;
+
+    /// Clear the jwt.
+    ///
+    /// The response will clear the jwt cookie, and any chunks of it,
+    /// (set them to empty with zero max_age) or Authorization: Bearer
+    /// or custom header (set it to empty).
+    fn clear_jwt(&mut self) -> Result<(), SessionError>;
+
+    /// Get the token last set on this response, if any.
+    ///
+    /// This is mainly useful with `TokenLocation::QueryParam`, where
+    /// the token is not sent by itself, but should be embedded by the
+    /// caller, e.g. in a link.
+    fn issued_jwt(&self) -> Option<String>;
+
+    /// Get an OAuth2 style json body for the token last set on this
+    /// response, if any.
+    ///
+    /// The body is on the form `{"access_token": "...", "token_type":
+    /// "Bearer", "expires_in": 86400}`, and is intended for
+    /// `TokenLocation::ResponseBody`, e.g.:
+    ///
+    /// ```ignore
+    /// res.set_jwt_user("carl")?;
+    /// let body = res.token_response().unwrap();
+    /// res.send(body)
+    /// ```
+    fn token_response(&self) -> Option<Json>;
+
+    /// Get the session metrics, e.g. to show in a status page.
+    ///
+    /// This is available for any response handled by a server that
+    /// utilizes the `SessionMiddleware`.
+    fn session_metrics(&self) -> Option<&Metrics>;
+
+    /// Create a signed token for the user and/or custom claims,
+    /// without adding it to the response.
+    fn make_jwt(
+        &self,
+        user: Option<&str>,
+        claims: Option<BTreeMap<String, Json>>,
+    ) -> Result<String, SessionError>;
+}
+
+impl<'a, 'b, D> SessionRequestExtensions for Request<'a, 'b, D> {
+    fn authorized_user(&self) -> Option<String> {
+        if let Some(session) = self.extensions().get::<Session>() {
+            debug!("Got a session: {:?}", session);
+            return Some(session.authorized_user.clone());
+        }
+        if let Some(verified) = lazy_token(self) {
+            return verified.context.registered.sub.clone();
+        }
+        debug!("authorized_user returning None");
+        None
+    }
+
+    fn valid_custom_claims(&self) -> Option<&BTreeMap<String, Json>> {
+        if let Some(custom_session) = self.extensions().get::<CustomSession>() {
+            debug!("Got a session with custom claims");
+            return Some(&custom_session.claims);
+        }
+        if let Some(verified) = lazy_token(self) {
+            let claims = &verified.context.custom_claims;
+            if !claims.is_empty() {
+                return Some(claims);
+            }
+        }
+        debug!("valid_custom_claims returning None");
+        None
+    }
+
+    fn claims<T: DeserializeOwned>(&self) -> Option<Result<T, ClaimsError>> {
+        self.valid_custom_claims().map(claims::from_claims)
+    }
+
+    fn current_user<U: 'static>(&self) -> Option<&U> {
+        self.extensions().get::<CurrentUser<U>>().or_else(|| {
+            lazy_token(self)
+                .and_then(|verified| verified.user.get::<CurrentUser<U>>())
+        })
+    }
+
+    fn auth_context(&self) -> Option<&AuthContext> {
+        self.extensions()
+            .get::<AuthContext>()
+            .or_else(|| lazy_token(self).map(|verified| &verified.context))
+    }
+
+    fn csrf_token(&self) -> Option<String> {
+        if let Some(csrf) = self.extensions().get::<CsrfToken>() {
+            return Some(csrf.token.clone());
+        }
+        let lazy = self.extensions().get::<LazyToken>()?;
+        if !lazy.csrf {
+            return None;
+        }
+        let jti = lazy.get()?.context.registered.jti.as_ref()?;
+        Some(lazy.middleware.config.core.csrf_token(jti))
+    }
+}
+
+/// Get the path of a request uri, without any query.
+fn request_path(uri: &RequestUri) -> Option<&str> {
+    match *uri {
+        RequestUri::AbsolutePath(ref path) => path.split('?').next(),
+        RequestUri::AbsoluteUri(ref url) => Some(url.path()),
+        _ => None,
+    }
+}
+
+/// Get the path of a request uri for logging.
+///
+/// The query is left out, as it may contain a token.
+fn logged_path(uri: &RequestUri) -> &str {
+    request_path(uri).unwrap_or("-")
+}
+
+/// Get the lazily verified token of a request, verifying it if needed.
+fn lazy_token<'r, 'a, 'b, D>(
+    req: &'r Request<'a, 'b, D>,
+) -> Option<&'r Verified> {
+    req.extensions().get::<LazyToken>().and_then(LazyToken::get)
+}
+
+impl<'a, 'b, D> SessionResponseExtensions for Response<'a, D> {
+    fn set_jwt_user(&mut self, user: &str) -> Result<(), SessionError> {
+        debug!("Should set a user jwt for {}", user);
+        set_new_jwt(self, TokenOptions {
+            subject: Some(user.to_owned()),
+            ..Default::default()
+        })
+    }
+
+    fn set_jwt_custom_claims(
+        &mut self,
+        claims: BTreeMap<String, Json>,
+    ) -> Result<(), SessionError> {
+        debug!("Should set custom claims jwt");
+        set_new_jwt(self, TokenOptions {
+            claims: claims,
+            ..Default::default()
+        })
+    }
+
+    fn set_jwt_user_and_custom_claims(
+        &mut self,
+        user: &str,
+        claims: BTreeMap<String, Json>,
+    ) -> Result<(), SessionError> {
+        debug!("Should set a user and custom claims jwt for {}", user);
+        set_new_jwt(self, TokenOptions {
+            subject: Some(user.to_owned()),
+            claims: claims,
+            ..Default::default()
+        })
+    }
+
+    fn set_jwt_claims<T: Serialize>(
+        &mut self,
+        claims: &T,
+    ) -> Result<(), SessionError> {
+        let claims = claims::to_claims(claims)?;
+        self.set_jwt_custom_claims(claims)
+    }
+
+    fn set_jwt_user_and_claims<T: Serialize>(
+        &mut self,
+        user: &str,
+        claims: &T,
+    ) -> Result<(), SessionError> {
+        let claims = claims::to_claims(claims)?;
+        self.set_jwt_user_and_custom_claims(user, claims)
+    }
+
+    fn issue_jwt<'r>(&'r mut self) -> TokenBuilder<'r, Self> {
+        TokenBuilder::new(self)
+    }
+
+    fn clear_jwt(&mut self) -> Result<(), SessionError> {
+        debug!("Should clear jwt");
+        let output = {
+            let sm = match self.extensions().get::<SessionMiddleware>() {
+                Some(sm) => sm,
+                None => return Err(SessionError::NoMiddleware),
+            };
+            let location = write_location(self, sm);
+            if let Some(request) = self.extensions().get::<AuditRequest>() {
+                sm.audit(AuditKind::Logout, request);
+            }
+            let chunks = old_cookie_chunks(self, sm, &location);
+            sm.config.core.clear(&location, chunks)
+        };
+        apply_output(self, output);
+        self.extensions_mut().remove::<IssuedToken>();
+        Ok(())
+    }
+
+    fn issued_jwt(&self) -> Option<String> {
+        self.extensions().get::<IssuedToken>().map(|t| t.token.clone())
+    }
+
+    fn token_response(&self) -> Option<Json> {
+        self.extensions()
+            .get::<IssuedToken>()
+            .map(|t| session_core::token_response(&t.token, t.expiration))
+    }
+
+    fn session_metrics(&self) -> Option<&Metrics> {
+        self.extensions()
+            .get::<SessionMiddleware>()
+            .map(|sm| sm.config.core.metrics())
+    }
+
+    fn make_jwt(
+        &self,
+        user: Option<&str>,
+        claims: Option<BTreeMap<String, Json>>,
+    ) -> Result<String, SessionError> {
+        match self.extensions().get::<SessionMiddleware>() {
+            Some(sm) => sm.config.core.make_token(TokenOptions {
+                subject: user.map(Into::into),
+                claims: claims.unwrap_or_default(),
+                ..Default::default()
+            }),
+            None => Err(SessionError::NoMiddleware),
+        }
+    }
+}
+
+/// Get the location to put a new token in for a response.
+fn write_location<'a, D>(
+    response: &Response<'a, D>,
+    sm: &SessionMiddleware,
+) -> TokenLocation {
+    let locations = sm.config.core.locations();
+    sm.config.core.location_for(
+        response
+            .extensions()
+            .get::<TokenFound>()
+            .and_then(|found| locations.get(found.index)),
+    )
+}
+
+/// Get the number of jwt cookie chunks the client currently has.
+fn old_cookie_chunks<'a, D>(
+    response: &Response<'a, D>,
+    sm: &SessionMiddleware,
+    location: &TokenLocation,
+) -> usize {
+    let chunks = match response.extensions().get::<CookieChunks>() {
+        Some(chunks) => chunks,
+        None => return 0,
+    };
+    sm.config
+        .core
+        .locations()
+        .iter()
+        .position(|l| l == location)
+        .and_then(|index| chunks.counts.get(index).cloned())
+        .unwrap_or(0)
+}
+
+/// Create a token with the given options and set it on the response.
+pub fn set_new_jwt<'a, D>(
+    response: &mut Response<'a, D>,
+    options: TokenOptions,
+) -> Result<(), SessionError> {
+    let issued = {
+        let sm = match response.extensions().get::<SessionMiddleware>() {
+            Some(sm) => sm,
+            None => return Err(SessionError::NoMiddleware),
+        };
+        let location = write_location(response, sm);
+        let chunks = old_cookie_chunks(response, sm, &location);
+        let subject = options.subject.clone();
+        let issued = sm.config.core.issue(options, &location, chunks)?;
+        if let Some(request) = response.extensions().get::<AuditRequest>() {
+            let request = AuditRequest {
+                subject: subject,
+                jti: issued.jti.clone(),
+                ..request.clone()
+            };
+            sm.audit(AuditKind::Login, &request);
+        }
+        issued
+    };
+    apply_output(response, issued.output);
+    response.extensions_mut().insert::<IssuedToken>(IssuedToken {
+        token: issued.token,
+        expiration: issued.expiration,
+    });
+    Ok(())
+}
+
+/// Add the output of the `SessionCore` to a response.
+fn apply_output<'a, D>(response: &mut Response<'a, D>, output: TokenOutput) {
+    match output {
+        TokenOutput::Cookies(cookies) => {
+            response.set(SetCookie(cookies));
+        }
+        TokenOutput::Header(name, value) => {
+            response.headers_mut().set_raw(name, vec![value.into_bytes()]);
+        }
+        TokenOutput::Nothing => {}
+    }
+}
+
+/// Get the csrf token submitted with a request, if any.
+///
+/// The form body is only read for form requests, so other kinds of
+/// request bodies are left for the handler.
+fn submitted_csrf_token<'mw, 'conn, D>(
+    req: &mut Request<'mw, 'conn, D>,
+    csrf: &CsrfProtection,
+) -> Option<String> {
+    let in_header = req.origin
+        .headers
+        .get_raw(csrf.header_name())
+        .and_then(|values| values.first())
+        .and_then(|v| String::from_utf8(v.clone()).ok());
+    if in_header.is_some() {
+        return in_header;
+    }
+    let is_form = req.origin
+        .headers
+        .get::<header::ContentType>()
+        .map(|ct| {
+            ct.to_string().starts_with("application/x-www-form-urlencoded")
+        })
+        .unwrap_or(false);
+    if is_form {
+        if let Ok(form) = req.form_body() {
+            return form.get(csrf.field_name()).map(|t| t.to_owned());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::sha2::Sha256;
+    use hyper::buffer::BufReader;
+    use hyper::header::Headers;
+    use hyper::net::NetworkStream;
+    use jwt::{Claims, Header, Registered, Token};
+    use nickel::{ReloadPolicy, TemplateCache};
+    use std::io::{self, Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration as StdDuration;
+
+    const KEY: &'static str = "A secret key for tests, at least 32 bytes";
+
+    /// A stream that reads a request from memory and discards writes.
+    struct MemoryStream {
+        input: io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl NetworkStream for MemoryStream {
+        fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+            Ok(([127, 0, 0, 1], 4711).into())
+        }
+
+        fn set_read_timeout(&self, _: Option<StdDuration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<StdDuration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Pass a request through the middleware, and then to `handler`
+    /// like a route would.
+    ///
+    /// Returns the status code if the middleware returns an error.
+    fn handle<F, T>(
+        middleware: &SessionMiddleware,
+        request: &str,
+        handler: F,
+    ) -> Result<T, StatusCode>
+    where
+        F: for<'r, 'mw, 'conn> FnOnce(
+            &'r mut Request<'mw, 'conn, ()>,
+            &'r mut Response<'mw, ()>,
+        ) -> T,
+    {
+        let mut stream = MemoryStream {
+            input: io::Cursor::new(request.as_bytes().to_owned()),
+        };
+        let addr = stream.peer_addr().unwrap();
+        let templates = TemplateCache::with_policy(ReloadPolicy::Never);
+        let stream: &mut dyn NetworkStream = &mut stream;
+        let mut reader = BufReader::new(stream);
+        let origin =
+            ::hyper::server::Request::new(&mut reader, addr).unwrap();
+        let mut req = Request::from_internal(origin, &());
+        let (mut output, mut headers) = (io::sink(), Headers::new());
+        let res = Response::from_internal(
+            ::hyper::server::Response::new(&mut output, &mut headers),
+            &templates,
+            &(),
+        );
+        let result = middleware.invoke(&mut req, res);
+        match result {
+            Ok(Continue(mut res)) => Ok(handler(&mut req, &mut res)),
+            Ok(::nickel::Halt(_)) => panic!("The middleware halted"),
+            Err(err) => Err(err.stream.map(|res| res.status()).unwrap()),
+        }
+    }
+
+    /// A request with the given method and extra header lines.
+    fn request(method: &str, headers: &[String]) -> String {
+        let mut request =
+            format!("{} / HTTP/1.1\r\nHost: localhost\r\n", method);
+        for header in headers {
+            request.push_str(header);
+            request.push_str("\r\n");
+        }
+        request.push_str("Content-Length: 0\r\n\r\n");
+        request
+    }
+
+    fn token_for(middleware: &SessionMiddleware, user: &str) -> String {
+        let options = TokenOptions {
+            subject: Some(user.to_owned()),
+            ..Default::default()
+        };
+        middleware.core().make_token(options).unwrap()
+    }
+
+    fn user<'mw, 'conn>(req: &mut Request<'mw, 'conn, ()>) -> Option<String> {
+        req.authorized_user()
+    }
+
+    #[test]
+    fn csrf_token_is_needed_for_state_changing_requests() {
+        let middleware = SessionMiddleware::new(KEY)
+            .csrf_protection(CsrfProtection::new());
+        let token = token_for(&middleware, "carl");
+        let cookie = format!("Cookie: jwt={}", token);
+
+        let get = request("GET", &[cookie.clone()]);
+        let result = handle(&middleware, &get, |req, _| {
+            (user(req), req.csrf_token())
+        });
+        let (user_name, csrf) = result.unwrap();
+        assert_eq!(user_name, Some("carl".to_owned()));
+        let csrf = csrf.unwrap();
+
+        let post = request("POST", &[cookie.clone()]);
+        assert_eq!(
+            handle(&middleware, &post, |req, _| user(req)),
+            Err(StatusCode::Forbidden)
+        );
+        let bad = request("POST", &[cookie.clone(), "X-CSRF-Token: x".into()]);
+        assert_eq!(
+            handle(&middleware, &bad, |req, _| user(req)),
+            Err(StatusCode::Forbidden)
+        );
+        let good =
+            request("POST", &[cookie, format!("X-CSRF-Token: {}", csrf)]);
+        assert_eq!(
+            handle(&middleware, &good, |req, _| user(req)),
+            Ok(Some("carl".to_owned()))
+        );
+
+        let bearer = format!("Authorization: Bearer {}", token);
+        let middleware = middleware
+            .using(TokenLocation::Cookie("jwt".to_owned()))
+            .also_using(TokenLocation::AuthorizationHeader);
+        assert_eq!(
+            handle(&middleware, &request("POST", &[bearer]), |req, _| {
+                user(req)
+            }),
+            Ok(Some("carl".to_owned()))
+        );
+    }
+
+    #[test]
+    fn session_without_jti_is_ignored_for_state_changing_requests() {
+        let middleware = SessionMiddleware::new(KEY)
+            .csrf_protection(CsrfProtection::new());
+        let claims = Claims::new(Registered {
+            sub: Some("carl".to_owned()),
+            exp: Some(::current_numeric_date() + 3600),
+            ..Default::default()
+        });
+        let token = Token::new(Header::default(), claims)
+            .signed(KEY.as_bytes(), Sha256::new())
+            .unwrap();
+        let cookie = format!("Cookie: jwt={}", token);
+
+        assert_eq!(
+            handle(&middleware, &request("GET", &[cookie.clone()]), |req, _| {
+                (user(req), req.csrf_token())
+            }),
+            Ok((Some("carl".to_owned()), None))
+        );
+        assert_eq!(
+            handle(&middleware, &request("POST", &[cookie]), |req, _| {
+                user(req)
+            }),
+            Ok(None)
+        );
+    }
+
+
+    #[derive(Debug, PartialEq)]
+    struct AppUser {
+        name: String,
+    }
+
+    /// A loader that counts its calls, and knows all users except
+    /// "nobody".
+    struct CountingLoader {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl UserLoader for CountingLoader {
+        type User = AppUser;
+
+        fn load(
+            &self,
+            subject: &str,
+            _claims: &BTreeMap<String, Json>,
+        ) -> Option<AppUser> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if subject == "nobody" {
+                None
+            } else {
+                Some(AppUser {
+                    name: subject.to_owned(),
+                })
+            }
+        }
+    }
+
+    #[test]
+    fn user_loader_runs_once_per_valid_token() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let middleware =
+            SessionMiddleware::new(KEY).user_loader(CountingLoader {
+                calls: calls.clone(),
+            });
+
+        let cookie = format!("Cookie: jwt={}", token_for(&middleware, "carl"));
+        let result = handle(&middleware, &request("GET", &[cookie]), |req, _| {
+            let first = req.current_user::<AppUser>().map(|u| u.name.clone());
+            let again = req.current_user::<AppUser>().map(|u| u.name.clone());
+            (first, again)
+        });
+        assert_eq!(
+            result,
+            Ok((Some("carl".to_owned()), Some("carl".to_owned())))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let result = handle(&middleware, &request("GET", &[]), |req, _| {
+            req.current_user::<AppUser>().is_some()
+        });
+        assert_eq!(result, Ok(false));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let bad = "Cookie: jwt=not.a.token".to_owned();
+        let result = handle(&middleware, &request("GET", &[bad]), |req, _| {
+            req.current_user::<AppUser>().is_some()
+        });
+        assert_eq!(result, Ok(false));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn user_loader_may_find_no_user() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let middleware =
+            SessionMiddleware::new(KEY).user_loader(CountingLoader {
+                calls: calls.clone(),
+            });
+
+        let token = token_for(&middleware, "nobody");
+        let cookie = format!("Cookie: jwt={}", token);
+        let result = handle(&middleware, &request("GET", &[cookie]), |req, _| {
+            (user(req), req.current_user::<AppUser>().is_some())
+        });
+        assert_eq!(result, Ok((Some("nobody".to_owned()), false)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+
+    #[test]
+    fn response_without_middleware() {
+        let templates = TemplateCache::with_policy(ReloadPolicy::Never);
+        let (mut output, mut headers) = (io::sink(), Headers::new());
+        let mut res: Response<()> = Response::from_internal(
+            ::hyper::server::Response::new(&mut output, &mut headers),
+            &templates,
+            &(),
+        );
+        match res.set_jwt_user("carl") {
+            Err(SessionError::NoMiddleware) => (),
+            other => panic!("Expected no middleware, got {:?}", other),
+        }
+        match res.issue_jwt().subject("carl").set() {
+            Err(SessionError::NoMiddleware) => (),
+            other => panic!("Expected no middleware, got {:?}", other),
+        }
+        match res.clear_jwt() {
+            Err(SessionError::NoMiddleware) => (),
+            other => panic!("Expected no middleware, got {:?}", other),
+        }
+        match res.make_jwt(Some("carl"), None) {
+            Err(SessionError::NoMiddleware) => (),
+            other => panic!("Expected no middleware, got {:?}", other),
+        }
+        assert!(res.headers().get::<SetCookie>().is_none());
+    }
+
+    #[test]
+    fn errors_issuing_tokens_are_returned() {
+        let middleware = SessionMiddleware::new(KEY);
+        let result = handle(&middleware, &request("GET", &[]), |_, res| {
+            let nested = res.issue_jwt().subject("carl").nested().set();
+            let expired =
+                res.issue_jwt().expires_in(Duration::seconds(-1)).set();
+            let claims = res.set_jwt_user_and_claims("carl", &vec![1, 2]);
+            let made = res.make_jwt(Some("carl"), None).is_ok();
+            let no_cookie = res.headers().get::<SetCookie>().is_none();
+            (nested, expired, claims, made, (no_cookie, res.issued_jwt()))
+        });
+        match result.unwrap() {
+            (
+                Err(SessionError::NoNestedKey),
+                Err(SessionError::BadExpiration(_)),
+                Err(SessionError::Claims(ClaimsError::NotAnObject)),
+                true,
+                (true, None),
+            ) => (),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+
+    #[test]
+    fn lazy_tokens_are_verified_once_on_first_access() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let middleware = SessionMiddleware::new(KEY)
+            .csrf_protection(CsrfProtection::new())
+            .user_loader(CountingLoader {
+                calls: calls.clone(),
+            })
+            .lazy_verification(true);
+        let verified = || middleware.core().metrics().snapshot().verified;
+        let cookie = format!("Cookie: jwt={}", token_for(&middleware, "carl"));
+
+        let get = request("GET", &[cookie.clone()]);
+        assert_eq!(handle(&middleware, &get, |_, _| ()), Ok(()));
+        assert_eq!((verified(), calls.load(Ordering::SeqCst)), (0, 0));
+
+        let result = handle(&middleware, &get, |req, _| {
+            let before = (verified(), calls.load(Ordering::SeqCst));
+            let first = user(req);
+            let again = user(req);
+            let current = req.current_user::<AppUser>().map(|u| u.name.clone());
+            (before, first, again, current, req.csrf_token())
+        });
+        let (before, first, again, current, csrf) = result.unwrap();
+        assert_eq!(before, (0, 0));
+        assert_eq!(first, Some("carl".to_owned()));
+        assert_eq!(again, first);
+        assert_eq!(current, first);
+        assert_eq!((verified(), calls.load(Ordering::SeqCst)), (1, 1));
+
+        // A state-changing cookie request is verified in invoke, for
+        // the csrf check.
+        let post = request("POST", &[cookie.clone()]);
+        assert_eq!(
+            handle(&middleware, &post, |_, _| ()),
+            Err(StatusCode::Forbidden)
+        );
+        let csrf = format!("X-CSRF-Token: {}", csrf.unwrap());
+        let post = request("POST", &[cookie, csrf]);
+        let result = handle(&middleware, &post, |req, _| {
+            let before = verified();
+            (before, user(req))
+        });
+        // Both posts are verified, also the forbidden one.
+        assert_eq!(result, Ok((3, Some("carl".to_owned()))));
+        assert_eq!(verified(), 3);
+    }
+}
@@ -0,0 +1,233 @@
+//! Nested tokens, signed with Ed25519 and then encrypted as a JWE.
+//!
+//! A nested token is for sharing with partner services.  The inner
+//! token is signed with our private key, so a partner can check that
+//! we issued it with our public key, and the signed token is encrypted
+//! with a key shared with the partner, so nobody else can read it.
+
+use crypto::ed25519;
+use jwe::{self, Encryption};
+use key::KeyError;
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+use std::io;
+
+/// The `cty` (content type) of a JWE containing a signed token.
+pub(crate) const NESTED_CTY: &'static str = "JWT";
+
+/// The length of Ed25519 seeds and public keys.
+const ED25519_KEY_LENGTH: usize = 32;
+
+/// Keys for nested tokens.
+///
+/// The encryption key is shared with the partner services, and is not
+/// the server key.  It is used as is, as the 32 byte AES-256 key of
+/// `dir` or the key wrapping key of `A256KW`, so partners can use any
+/// JOSE library.  To issue nested tokens, a signing key is needed.
+/// To accept nested tokens, the public keys of their issuers are
+/// needed; the public key of our own signing key is always accepted.
+///
+/// ```ignore
+/// let nested = NestedTokens::new(Encryption::KeyWrap, &partner_key)?
+///     .sign_with(&our_ed25519_seed)?
+///     .accept_signer(&partner_public_key)?;
+/// ```
+#[derive(Clone)]
+pub struct NestedTokens {
+    encryption: Encryption,
+    /// The content encryption key, or key wrapping key.
+    key: Vec<u8>,
+    /// The Ed25519 secret key, for signing.
+    secret_key: Option<Vec<u8>>,
+    /// The Ed25519 public keys accepted for verifying.
+    public_keys: Vec<Vec<u8>>,
+}
+
+impl NestedTokens {
+    /// Create a new instance with a key shared with partner services.
+    ///
+    /// The key must be exactly 32 bytes, and should be random.
+    pub fn new(
+        encryption: Encryption,
+        shared_key: &[u8],
+    ) -> Result<NestedTokens, KeyError> {
+        if shared_key.len() != jwe::KEY_LENGTH {
+            return Err(KeyError::WrongLength(
+                shared_key.len(),
+                jwe::KEY_LENGTH,
+            ));
+        }
+        Ok(NestedTokens {
+            encryption: encryption,
+            key: shared_key.to_owned(),
+            secret_key: None,
+            public_keys: Vec::new(),
+        })
+    }
+
+    /// Sign nested tokens with the Ed25519 key from a 32 byte seed.
+    pub fn sign_with(mut self, seed: &[u8]) -> Result<Self, KeyError> {
+        check_length(seed)?;
+        let (secret_key, public_key) = ed25519::keypair(seed);
+        self.secret_key = Some(secret_key.to_vec());
+        self.public_keys.push(public_key.to_vec());
+        Ok(self)
+    }
+
+    /// Accept nested tokens signed by the given Ed25519 public key.
+    pub fn accept_signer(
+        mut self,
+        public_key: &[u8],
+    ) -> Result<Self, KeyError> {
+        check_length(public_key)?;
+        self.public_keys.push(public_key.to_owned());
+        Ok(self)
+    }
+
+    /// Get the Ed25519 public key of the signing key, if any.
+    ///
+    /// Give this to the partner services that should accept our
+    /// nested tokens.
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.secret_key.as_ref().map(|secret| &secret[32..])
+    }
+
+    /// Sign the base64 encoded claims and encrypt the signed token.
+    ///
+    /// The `key_id`, if any, is the `kid` of both the inner and the
+    /// outer header.  Returns None if there is no signing key.
+    pub(crate) fn seal(
+        &self,
+        claims: &str,
+        key_id: Option<&str>,
+    ) -> Option<Result<String, io::Error>> {
+        let secret_key = self.secret_key.as_ref()?;
+        let mut header = BTreeMap::new();
+        header.insert("alg".to_owned(), Json::String("EdDSA".to_owned()));
+        header.insert("typ".to_owned(), Json::String("JWT".to_owned()));
+        if let Some(key_id) = key_id {
+            header.insert("kid".to_owned(), Json::String(key_id.to_owned()));
+        }
+        let header = Json::Object(header).to_string();
+        let header = header.as_bytes().to_base64(URL_SAFE);
+        let input = format!("{}.{}", header, claims);
+        let signature = ed25519::signature(input.as_bytes(), secret_key);
+        let signed = format!("{}.{}", input, signature.to_base64(URL_SAFE));
+        Some(jwe::encrypt(
+            self.encryption,
+            &self.key,
+            Some(NESTED_CTY),
+            key_id,
+            signed.as_bytes(),
+        ))
+    }
+
+    /// Decrypt a nested token.
+    pub(crate) fn decrypt(
+        &self,
+        token: &str,
+    ) -> Result<jwe::Decrypted, jwe::DecryptError> {
+        jwe::decrypt(&self.key, token)
+    }
+
+    /// Check the signature of an inner token, and get its base64
+    /// encoded claims.
+    pub(crate) fn verify_inner(&self, inner: &[u8]) -> Result<String, Inner> {
+        let inner = ::std::str::from_utf8(inner).map_err(|_| Inner::Malformed)?;
+        let parts = inner.split('.').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Err(Inner::Malformed);
+        }
+        let header = parts[0]
+            .from_base64()
+            .ok()
+            .and_then(|header| String::from_utf8(header).ok())
+            .and_then(|header| Json::from_str(&header).ok())
+            .ok_or(Inner::Malformed)?;
+        if header.find("alg").and_then(Json::as_string) != Some("EdDSA") {
+            return Err(Inner::Malformed);
+        }
+        let signature = parts[2].from_base64().map_err(|_| Inner::Malformed)?;
+        if signature.len() != 64 {
+            return Err(Inner::Malformed);
+        }
+        let input = &inner[..parts[0].len() + 1 + parts[1].len()];
+        let valid = self.public_keys.iter().any(|public_key| {
+            ed25519::verify(input.as_bytes(), public_key, &signature)
+        });
+        if valid {
+            Ok(parts[1].to_owned())
+        } else {
+            Err(Inner::BadSignature)
+        }
+    }
+}
+
+/// The reason an inner token was rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Inner {
+    /// The inner token is not an Ed25519 signed token.
+    Malformed,
+    /// The signature is not from an accepted key.
+    BadSignature,
+}
+
+fn check_length(key: &[u8]) -> Result<(), KeyError> {
+    if key.len() == ED25519_KEY_LENGTH {
+        Ok(())
+    } else {
+        Err(KeyError::WrongLength(key.len(), ED25519_KEY_LENGTH))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let issuer = NestedTokens::new(Encryption::KeyWrap, &[1; 32])
+            .unwrap()
+            .sign_with(&[2; 32])
+            .unwrap();
+        let public_key = issuer.public_key().unwrap().to_owned();
+        let partner = NestedTokens::new(Encryption::KeyWrap, &[1; 32])
+            .unwrap()
+            .accept_signer(&public_key)
+            .unwrap();
+        let token = issuer.seal("e30", Some("k1")).unwrap().unwrap();
+        let decrypted = partner.decrypt(&token).unwrap();
+        let inner = decrypted.plaintext.clone();
+        assert_eq!(decrypted.cty(), Some(NESTED_CTY));
+        assert_eq!(
+            decrypted.header.get("kid").and_then(Json::as_string),
+            Some("k1")
+        );
+        assert_eq!(partner.verify_inner(&inner), Ok("e30".to_owned()));
+
+        let stranger = NestedTokens::new(Encryption::KeyWrap, &[1; 32])
+            .unwrap()
+            .accept_signer(&[9; 32])
+            .unwrap();
+        assert_eq!(stranger.verify_inner(&inner), Err(Inner::BadSignature));
+        assert!(NestedTokens::new(Encryption::Direct, &[3; 32])
+            .unwrap()
+            .decrypt(&token)
+            .is_err());
+    }
+
+    #[test]
+    fn shared_key_is_used_as_is() {
+        let nested = NestedTokens::new(Encryption::KeyWrap, &[1; 32])
+            .unwrap()
+            .sign_with(&[2; 32])
+            .unwrap();
+        let token = nested.seal("e30", None).unwrap().unwrap();
+        assert!(jwe::decrypt(&[1; 32], &token).is_ok());
+        assert_eq!(
+            NestedTokens::new(Encryption::KeyWrap, &[1; 40]).err(),
+            Some(KeyError::WrongLength(40, 32))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Selecting the request paths that a middleware handles.
+
+/// A pattern for request paths.
+///
+/// A `*` in the pattern matches any sequence of characters, including
+/// `/`, so `/static/*` matches everything below `/static/`.  A pattern
+/// without `*` matches only that exact path.
+#[derive(Clone, Debug, PartialEq)]
+enum PathPattern {
+    Exact(String),
+    Prefix(String),
+    Glob(String),
+}
+
+impl PathPattern {
+    fn new(pattern: &str) -> PathPattern {
+        match pattern.find('*') {
+            None => PathPattern::Exact(pattern.to_owned()),
+            Some(i) if i == pattern.len() - 1 => {
+                PathPattern::Prefix(pattern[..i].to_owned())
+            }
+            Some(_) => PathPattern::Glob(pattern.to_owned()),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match *self {
+            PathPattern::Exact(ref exact) => path == exact,
+            PathPattern::Prefix(ref prefix) => path.starts_with(prefix),
+            PathPattern::Glob(ref glob) => glob_match(glob, path),
+        }
+    }
+}
+
+/// Match a path against a pattern where `*` matches any sequence.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut rest = &path[first.len()..];
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = parts.pop().unwrap_or("");
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Include and exclude patterns for request paths.
+///
+/// A path is selected if it matches any include pattern, or if there
+/// are no include patterns, and it matches no exclude pattern.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PathFilter {
+    include: Vec<PathPattern>,
+    exclude: Vec<PathPattern>,
+}
+
+impl PathFilter {
+    pub(crate) fn include(&mut self, pattern: &str) {
+        self.include.push(PathPattern::new(pattern));
+    }
+
+    pub(crate) fn exclude(&mut self, pattern: &str) {
+        self.exclude.push(PathPattern::new(pattern));
+    }
+
+    /// Check if a path is selected by this filter.
+    pub(crate) fn selects(&self, path: &str) -> bool {
+        (self.include.is_empty() ||
+            self.include.iter().any(|p| p.matches(path))) &&
+            !self.exclude.iter().any(|p| p.matches(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclude_static_and_health() {
+        let mut filter = PathFilter::default();
+        filter.exclude("/static/*");
+        filter.exclude("/health");
+        filter.exclude("/*.ico");
+        assert!(filter.selects("/"));
+        assert!(filter.selects("/healthy"));
+        assert!(filter.selects("/static"));
+        assert!(!filter.selects("/static/css/site.css"));
+        assert!(!filter.selects("/health"));
+        assert!(!filter.selects("/favicon.ico"));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let mut filter = PathFilter::default();
+        filter.include("/api/*");
+        filter.exclude("/api/*/public");
+        assert!(filter.selects("/api/users"));
+        assert!(!filter.selects("/api/users/public"));
+        assert!(!filter.selects("/login"));
+    }
+}
@@ -0,0 +1,109 @@
+//! Redaction of tokens and claims in log messages.
+//!
+//! A token in a log is as good as a password, so tokens are only
+//! logged as a fingerprint, a truncated hash that can be matched
+//! between log lines but not used.  Claim values may be personal
+//! data, so they are masked unless configured to be shown.
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use jwt::Registered;
+use rustc_serialize::json::Json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// The number of hex digits of the hash shown in a fingerprint.
+const FINGERPRINT_LENGTH: usize = 12;
+
+/// Get a fingerprint of a token, to log instead of the token itself.
+pub fn fingerprint(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    let mut hash = hasher.result_str();
+    hash.truncate(FINGERPRINT_LENGTH);
+    format!("jwt#{}", hash)
+}
+
+/// Policy for what claim values to show in logs.
+///
+/// By default, the names of all custom claims are logged, but all
+/// values are masked.  The registered claims `iss`, `sub`, `aud`,
+/// `exp`, `nbf` and `iat` are shown, and the `jti` is fingerprinted.
+#[derive(Clone, Debug, Default)]
+pub struct Redaction {
+    shown: BTreeSet<String>,
+}
+
+impl Redaction {
+    /// Create a new instance, that masks all custom claim values.
+    pub fn new() -> Redaction {
+        Redaction::default()
+    }
+
+    /// Show the value of the named custom claim in logs.
+    pub fn show_claim(mut self, name: &str) -> Self {
+        self.shown.insert(name.to_owned());
+        self
+    }
+
+    /// Get custom claims as a string to log.
+    pub fn claims(&self, claims: &BTreeMap<String, Json>) -> String {
+        let mut out = String::from("{");
+        for (i, (name, value)) in claims.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            if self.shown.contains(name) {
+                let _ = write!(out, "{:?}: {}", name, value);
+            } else {
+                let _ = write!(out, "{:?}: ***", name);
+            }
+        }
+        out.push('}');
+        out
+    }
+
+    /// Get registered and custom claims as a string to log.
+    pub fn all_claims(
+        &self,
+        reg: &Registered,
+        custom: &BTreeMap<String, Json>,
+    ) -> String {
+        format!(
+            "iss: {:?}, sub: {:?}, aud: {:?}, exp: {:?}, nbf: {:?}, \
+             iat: {:?}, jti: {:?}, custom: {}",
+            reg.iss,
+            reg.sub,
+            reg.aud,
+            reg.exp,
+            reg.nbf,
+            reg.iat,
+            reg.jti.as_ref().map(|jti| fingerprint(jti)),
+            self.claims(custom),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_are_masked_by_default() {
+        let mut claims = BTreeMap::new();
+        claims.insert("email".to_owned(), Json::String("a@b.c".into()));
+        claims.insert("admin".to_owned(), Json::Boolean(true));
+        let redaction = Redaction::new().show_claim("admin");
+        assert_eq!(
+            redaction.claims(&claims),
+            r#"{"admin": true, "email": ***}"#
+        );
+    }
+
+    #[test]
+    fn fingerprint_hides_token() {
+        let print = fingerprint("header.claims.signature");
+        assert_eq!(print.len(), 4 + FINGERPRINT_LENGTH);
+        assert!(!print.contains("claims"));
+    }
+}
@@ -0,0 +1,15 @@
+//! Checking if a valid token has been revoked.
+
+use context::AuthContext;
+
+/// A hook for rejecting tokens that are properly signed and not
+/// expired, but revoked, e.g. by a logout on another device.
+///
+/// Implement this for e.g. a set of revoked `jti` values, and register
+/// it with `SessionCore::revocation_check()`.  The check is done for
+/// every request with a valid token, also when the verified token is
+/// found in the cache of the `SessionMiddleware`, so it should be fast.
+pub trait RevocationCheck: Send + Sync + 'static {
+    /// Check if a valid token is revoked.
+    fn is_revoked(&self, context: &AuthContext) -> bool;
+}
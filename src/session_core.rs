@@ -0,0 +1,1621 @@
+//! Framework-agnostic token handling.
+//!
+//! The `SessionCore` issues and validates tokens from plain header,
+//! cookie and query inputs, and describes what to add to a response
+//! to set or clear a token.  The nickel `SessionMiddleware` is a thin
+//! adapter around it, and other services can use it directly to share
+//! exactly the same session semantics and keys.
+
+use claims::{self, ClaimsError};
+use context::{AuthContext, TokenHeader};
+use cookie::Cookie;
+use crypto::sha2::Sha256;
+use csrf;
+use error::SessionError;
+use jwe::{self, DecryptError, Encryption};
+use jwt::{Claims, Component, Header, Registered, Token};
+use key::{self, KeyError};
+use keyring::Keyring;
+use metrics::Metrics;
+use nested::{Inner, NestedTokens, NESTED_CTY};
+use rand::{OsRng, Rng};
+use redact::{fingerprint, Redaction};
+use revocation::RevocationCheck;
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use serde::Serialize;
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use time::Duration;
+
+/// Configuration and logic for issuing and validating tokens.
+#[derive(Clone)]
+pub struct SessionCore {
+    /// The keys for signing jwts.  Should be kept private, but needs
+    /// to be the same on multiple servers sharing a jwt domain.
+    keyring: Keyring,
+    /// Value for the iss (issuer) jwt claim.
+    issuer: Option<String>,
+    /// Value for the aud (audience) jwt claim.
+    audience: Option<String>,
+    /// How long a token should be valid after creation, in seconds
+    expiration_time: Duration,
+    /// Where to look for the token, in order.
+    locations: Vec<TokenLocation>,
+    /// Which of the locations to put the token to be returned in.
+    write_location: WriteLocation,
+    /// Attributes for token cookies.
+    cookie: CookieAttributes,
+    /// What claim values to show in logs.
+    redaction: Redaction,
+    /// Counters for verification outcomes.
+    metrics: Metrics,
+    /// Hook for rejecting revoked tokens, if any.
+    revocation: Option<Arc<dyn RevocationCheck>>,
+    /// How to encrypt new tokens, if at all.
+    encryption: Option<Encryption>,
+    /// Keys for nested tokens, if any.
+    nested: Option<NestedTokens>,
+}
+
+/// Attributes of the cookies holding a token.
+#[derive(Clone, Debug, Default)]
+struct CookieAttributes {
+    secure: bool,
+    http_only: bool,
+    path: Option<String>,
+    domain: Option<String>,
+}
+
+impl CookieAttributes {
+    fn cookie(
+        &self,
+        name: String,
+        value: String,
+        max_age: Duration,
+    ) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name, value)
+            .max_age(max_age)
+            .secure(self.secure)
+            .http_only(self.http_only);
+        if let Some(ref path) = self.path {
+            cookie = cookie.path(path.clone());
+        }
+        if let Some(ref domain) = self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        cookie.finish()
+    }
+
+    /// A cookie that removes the named cookie from the client.
+    fn removal(&self, name: String) -> String {
+        self.cookie(name, String::new(), Duration::seconds(0))
+            .to_string()
+    }
+}
+
+/// Places the token could be located.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenLocation {
+    /// A cookie with the given name.
+    Cookie(String),
+    /// The `Authorization: Bearer` header.
+    AuthorizationHeader,
+    /// A query parameter with the given name, e.g. for WebSocket
+    /// connections or download links.
+    ///
+    /// A token can not be put in a query parameter of a response, so
+    /// when a new token is created for this location, it is up to
+    /// the caller to get it by `issued_jwt()` and embed it in a link.
+    QueryParam(String),
+    /// A custom header with the given name, e.g. `X-Auth-Token`.
+    Header(String),
+    /// For api logins, where the token is returned in a json response
+    /// body and then sent by the client in the `Authorization: Bearer`
+    /// header.
+    ///
+    /// A new token is not added to the response by itself, use
+    /// `token_response()` to get the json body to send.
+    ResponseBody,
+}
+
+impl FromStr for TokenLocation {
+    type Err = String;
+
+    /// Parse a location from `cookie`, `cookie:NAME`, `authorization`,
+    /// `header:NAME`, `query:NAME` or `body`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        match (kind, parts.next().map(|name| name.trim())) {
+            ("cookie", None) => Ok(TokenLocation::Cookie("jwt".to_owned())),
+            ("cookie", Some(name)) if !name.is_empty() => {
+                Ok(TokenLocation::Cookie(name.to_owned()))
+            }
+            ("authorization", None) => Ok(TokenLocation::AuthorizationHeader),
+            ("header", Some(name)) if !name.is_empty() => {
+                Ok(TokenLocation::Header(name.to_owned()))
+            }
+            ("query", Some(name)) if !name.is_empty() => {
+                Ok(TokenLocation::QueryParam(name.to_owned()))
+            }
+            ("body", None) => Ok(TokenLocation::ResponseBody),
+            _ => Err(format!("Unknown token location {:?}", s)),
+        }
+    }
+}
+
+/// Rule for where a new token is put when more than one location is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteLocation {
+    /// Always put the token in the first configured location.
+    First,
+    /// Put the token where the token of the request was found, or in
+    /// the first configured location if the request had no token.
+    Mirror,
+}
+
+/// Options for a token to create.
+///
+/// Anything not set falls back to the configuration of the
+/// `SessionCore`.
+#[derive(Debug, Default)]
+pub struct TokenOptions {
+    /// The sub (subject) claim, i.e. the username.
+    pub subject: Option<String>,
+    /// The aud (audience) claim.
+    pub audience: Option<String>,
+    /// How long the token should be valid after creation.
+    pub expiration: Option<Duration>,
+    /// The kid (key id) header.
+    pub key_id: Option<String>,
+    /// Custom claims.
+    pub claims: BTreeMap<String, Json>,
+    /// Create a nested token, signed with the `NestedTokens` signing
+    /// key and encrypted with the key shared with partner services.
+    pub nested: bool,
+}
+
+impl TokenOptions {
+    /// Add custom claims from a typed value.
+    ///
+    /// The value must serialize to a json object.
+    pub fn add_claims<T: Serialize>(
+        &mut self,
+        claims: &T,
+    ) -> Result<(), ClaimsError> {
+        self.claims.extend(claims::to_claims(claims)?);
+        Ok(())
+    }
+}
+
+/// Access to the parts of a request where a token may be located.
+///
+/// Implement this for the request type of a web framework to find
+/// tokens with `SessionCore::find_token()`, or use `RequestParts`.
+pub trait TokenSource {
+    /// Get the value of the named cookie.
+    fn cookie(&self, name: &str) -> Option<String>;
+
+    /// Get the value of the named header.
+    fn header(&self, name: &str) -> Option<String>;
+
+    /// Get the value of the named query parameter.
+    fn query_param(&mut self, name: &str) -> Option<String>;
+}
+
+/// A `TokenSource` built from plain header values and a query string.
+#[derive(Debug, Default)]
+pub struct RequestParts {
+    headers: Vec<(String, String)>,
+    cookies: BTreeMap<String, String>,
+    query: BTreeMap<String, String>,
+}
+
+impl RequestParts {
+    /// Create a new, empty, instance.
+    pub fn new() -> RequestParts {
+        RequestParts::default()
+    }
+
+    /// Add a header.
+    ///
+    /// Any `Cookie` header is also parsed into cookies.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        if name.eq_ignore_ascii_case("cookie") {
+            for part in value.split(';') {
+                if let Ok(cookie) = Cookie::parse(part.trim()) {
+                    self.cookies.insert(
+                        cookie.name().to_string(),
+                        cookie.value().to_string(),
+                    );
+                }
+            }
+        }
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Set the query string (the part of the uri after `?`).
+    pub fn query(mut self, query: &str) -> Self {
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let name = percent_decode(parts.next().unwrap_or(""));
+            let value = percent_decode(parts.next().unwrap_or(""));
+            self.query.entry(name).or_insert(value);
+        }
+        self
+    }
+}
+
+impl TokenSource for RequestParts {
+    fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies.get(name).cloned()
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.clone())
+    }
+
+    fn query_param(&mut self, name: &str) -> Option<String> {
+        self.query.get(name).cloned()
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut result = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => result.push(b' '),
+            b'%' => {
+                let hex = bytes.clone().take(2).collect::<Vec<u8>>();
+                match ::std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(decoded) if hex.len() == 2 => {
+                        result.push(decoded);
+                        bytes.nth(1);
+                    }
+                    _ => result.push(b'%'),
+                }
+            }
+            b => result.push(b),
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// A token found in a request.
+pub struct FoundToken {
+    /// The token itself.
+    pub token: String,
+    /// Where the token was found.
+    pub location: TokenLocation,
+}
+
+impl fmt::Debug for FoundToken {
+    // Only a fingerprint of the token, so it can't leak through logs.
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.debug_struct("FoundToken")
+            .field("token", &fingerprint(&self.token))
+            .field("location", &self.location)
+            .finish()
+    }
+}
+
+/// The reason a token was rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// The token could not be parsed.
+    Malformed,
+    /// The token signature is not valid for the key, or the token
+    /// could not be decrypted with the key.
+    BadSignature,
+    /// The nbf (not before) time of the token is in the future.
+    NotYetValid,
+    /// The exp (expiration) time of the token is in the past.
+    Expired,
+    /// The aud (audience) of the token is not the configured one.
+    WrongAudience,
+    /// The token is revoked, according to the `RevocationCheck`.
+    Revoked,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.write_str(match *self {
+            Rejection::Malformed => "malformed token",
+            Rejection::BadSignature => "bad signature",
+            Rejection::NotYetValid => "token not yet valid",
+            Rejection::Expired => "token expired",
+            Rejection::WrongAudience => "wrong audience",
+            Rejection::Revoked => "token revoked",
+        })
+    }
+}
+
+/// A rejected token.
+#[derive(Debug)]
+pub(crate) struct Rejected {
+    pub(crate) rejection: Rejection,
+    /// The data of the token, if it is authentic but was rejected for
+    /// its claims, e.g. because it is expired.
+    #[cfg_attr(not(feature = "middleware"), allow(dead_code))]
+    pub(crate) context: Option<AuthContext>,
+}
+
+impl From<Rejection> for Rejected {
+    fn from(rejection: Rejection) -> Rejected {
+        Rejected {
+            rejection: rejection,
+            context: None,
+        }
+    }
+}
+
+/// What to add to a response to set or clear a token.
+#[derive(PartialEq)]
+pub enum TokenOutput {
+    /// `Set-Cookie` header values.
+    Cookies(Vec<String>),
+    /// A header name and value.
+    Header(String, String),
+    /// Nothing, the caller is responsible for sending the token.
+    Nothing,
+}
+
+impl fmt::Debug for TokenOutput {
+    // The cookies and header value contain the token.
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenOutput::Cookies(ref cookies) => {
+                let cookies: Vec<_> =
+                    cookies.iter().map(|c| fingerprint(c)).collect();
+                out.debug_tuple("Cookies").field(&cookies).finish()
+            }
+            TokenOutput::Header(ref name, ref value) => out
+                .debug_tuple("Header")
+                .field(name)
+                .field(&fingerprint(value))
+                .finish(),
+            TokenOutput::Nothing => out.write_str("Nothing"),
+        }
+    }
+}
+
+/// A new token, and what to add to a response to send it.
+pub struct IssuedToken {
+    /// The signed token.
+    pub token: String,
+    /// How long the token is valid.
+    pub expiration: Duration,
+    /// The jti (jwt id) claim of the token.
+    pub jti: Option<String>,
+    /// What to add to the response.
+    pub output: TokenOutput,
+}
+
+impl fmt::Debug for IssuedToken {
+    // Only a fingerprint of the token, so it can't leak through logs.
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.debug_struct("IssuedToken")
+            .field("token", &fingerprint(&self.token))
+            .field("expiration", &self.expiration)
+            .field("jti", &self.jti)
+            .field("output", &self.output)
+            .finish()
+    }
+}
+
+impl SessionCore {
+    /// Create a new instance.
+    ///
+    /// The `server_key` is used for signing and validating the jwt token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is shorter than `key::MIN_KEY_LENGTH` bytes.
+    /// Use `try_new()` to handle a weak key as an error.
+    pub fn new(server_key: &str) -> SessionCore {
+        match SessionCore::try_new(server_key.as_bytes()) {
+            Ok(core) => core,
+            Err(err) => panic!("Bad server key: {}", err),
+        }
+    }
+
+    /// Create a new instance, with raw key bytes.
+    ///
+    /// Keys shorter than `key::MIN_KEY_LENGTH` bytes are rejected.
+    /// See the `key` module for deriving a strong key from a
+    /// passphrase, or loading one from a file.
+    pub fn try_new(server_key: &[u8]) -> Result<SessionCore, KeyError> {
+        key::check_key(server_key)?;
+        Ok(SessionCore::with_weak_key(server_key))
+    }
+
+    /// Create a new instance without checking the key strength.
+    ///
+    /// This is only for compatibility with tokens signed by existing
+    /// weak keys.  Replace the key as soon as possible.
+    pub fn with_weak_key(server_key: &[u8]) -> SessionCore {
+        SessionCore::from_keyring(Keyring::with_weak_key(server_key))
+    }
+
+    /// Create a new instance, using the keys of a `Keyring`.
+    ///
+    /// Replacing the key of the keyring takes effect immediately.
+    pub fn from_keyring(keyring: Keyring) -> SessionCore {
+        SessionCore {
+            keyring: keyring,
+            issuer: None,
+            audience: None,
+            expiration_time: Duration::days(1),
+            locations: vec![TokenLocation::Cookie("jwt".to_owned())],
+            write_location: WriteLocation::First,
+            cookie: CookieAttributes::default(),
+            redaction: Redaction::new(),
+            metrics: Metrics::new(),
+            revocation: None,
+            encryption: None,
+            nested: None,
+        }
+    }
+
+    /// Set a value for the iss (issuer) jwt claim.
+    ///
+    /// The default is to not set an issuer.
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_owned());
+        self
+    }
+
+    /// Set a value for the aud (audience) jwt claim.
+    ///
+    /// When set, new tokens get this audience by default, and tokens
+    /// for any other audience are rejected.  The default is to not
+    /// use an audience.
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_owned());
+        self
+    }
+
+    /// Set how long a token should be valid after creation (in seconds).
+    ///
+    /// The default is 24 hours.
+    pub fn expiration_time(mut self, expiration_time: Duration) -> Self {
+        self.expiration_time = expiration_time;
+        self
+    }
+
+    /// Set where the token should be stored.
+    ///
+    /// The default is `TokenLocation::Cookie("jwt")`. Alternatively,
+    /// the token can be set in the `Authorization: Bearer` header.
+    ///
+    /// This replaces any previously configured locations.
+    pub fn using(mut self, location: TokenLocation) -> Self {
+        self.locations = vec![location];
+        self
+    }
+
+    /// Also look for the token in another location.
+    ///
+    /// Locations are searched in the order they are added, and the
+    /// first token found is used.
+    pub fn also_using(mut self, location: TokenLocation) -> Self {
+        self.locations.push(location);
+        self
+    }
+
+    /// Set where new tokens are put when more than one location is used.
+    ///
+    /// The default is `WriteLocation::First`.
+    pub fn write_location(mut self, write_location: WriteLocation) -> Self {
+        self.write_location = write_location;
+        self
+    }
+
+    /// Set the `Secure` attribute of token cookies.
+    ///
+    /// A secure cookie is only sent over https.  The default is false,
+    /// but should be true for any service not only served over http.
+    pub fn cookie_secure(mut self, secure: bool) -> Self {
+        self.cookie.secure = secure;
+        self
+    }
+
+    /// Set the `HttpOnly` attribute of token cookies.
+    ///
+    /// An http only cookie is not available to scripts.  The default
+    /// is false.
+    pub fn cookie_http_only(mut self, http_only: bool) -> Self {
+        self.cookie.http_only = http_only;
+        self
+    }
+
+    /// Set the `Path` attribute of token cookies.
+    ///
+    /// The default is to not set a path.
+    pub fn cookie_path(mut self, path: &str) -> Self {
+        self.cookie.path = Some(path.to_owned());
+        self
+    }
+
+    /// Set the `Domain` attribute of token cookies.
+    ///
+    /// The default is to not set a domain.
+    pub fn cookie_domain(mut self, domain: &str) -> Self {
+        self.cookie.domain = Some(domain.to_owned());
+        self
+    }
+
+    /// Set what claim values to show in logs.
+    ///
+    /// Tokens are always logged as a fingerprint.  The default is to
+    /// mask all custom claim values.
+    pub fn redact_logs(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Encrypt new tokens, so their claims are not readable by clients.
+    ///
+    /// The tokens are JWEs, encrypted with AES-256-GCM with a key
+    /// derived from the server key.  Both encrypted and signed tokens
+    /// are accepted, so existing sessions stay valid when encryption
+    /// is enabled.
+    ///
+    /// The default is to sign tokens without encrypting them.
+    pub fn encrypt_tokens(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Set the keys for nested tokens, shared with partner services.
+    ///
+    /// With a signing key, nested tokens are issued with the `nested`
+    /// token option.  Nested tokens from the accepted signers are
+    /// verified like other tokens.  See `NestedTokens`.
+    ///
+    /// The default is to not issue or accept nested tokens.
+    pub fn nested_tokens(mut self, nested: NestedTokens) -> Self {
+        self.nested = Some(nested);
+        self
+    }
+
+    /// Set a hook for rejecting revoked tokens.
+    ///
+    /// The hook is called for each token that is properly signed and
+    /// currently valid, and a revoked token is rejected with
+    /// `Rejection::Revoked`.
+    ///
+    /// The default is to not check for revoked tokens.
+    pub fn revocation_check<R: RevocationCheck>(mut self, check: R) -> Self {
+        self.revocation = Some(Arc::new(check));
+        self
+    }
+
+    /// Get the policy for what claim values to show in logs.
+    pub fn redaction(&self) -> &Redaction {
+        &self.redaction
+    }
+
+    /// Get the counters for verification outcomes.
+    ///
+    /// The counters are shared by all clones of this instance.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Get the keyring of this instance.
+    ///
+    /// Use it to replace the key at runtime, or to watch a key file.
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// Get the configured token locations, in search order.
+    pub fn locations(&self) -> &[TokenLocation] {
+        &self.locations
+    }
+
+    /// Find the token of a request.
+    pub fn find_token<S: TokenSource>(
+        &self,
+        source: &mut S,
+    ) -> Option<FoundToken> {
+        for location in &self.locations {
+            let found = match *location {
+                TokenLocation::Cookie(ref name) => get_cookie(source, name),
+                TokenLocation::AuthorizationHeader |
+                TokenLocation::ResponseBody => {
+                    source.header("Authorization").and_then(|value| {
+                        if value.starts_with("Bearer ") {
+                            Some(value["Bearer ".len()..].trim().to_owned())
+                        } else {
+                            None
+                        }
+                    })
+                }
+                TokenLocation::QueryParam(ref name) => source.query_param(name),
+                TokenLocation::Header(ref name) => source.header(name),
+            };
+            if let Some(token) = found {
+                debug!("Found a token in {:?}", location);
+                return Some(FoundToken {
+                    token: token,
+                    location: location.clone(),
+                });
+            }
+        }
+        None
+    }
+
+    /// Verify a token.
+    ///
+    /// If the token is properly signed and currently valid, all its
+    /// data is returned.
+    pub fn verify(&self, token: &str) -> Result<AuthContext, Rejection> {
+        self.verify_detailed(token).map_err(|rejected| rejected.rejection)
+    }
+
+    /// Verify a token, keeping the data of a rejected but authentic
+    /// token, e.g. for audit events.
+    pub(crate) fn verify_detailed(
+        &self,
+        token: &str,
+    ) -> Result<AuthContext, Rejected> {
+        let result = self.check(token);
+        self.count(&result);
+        result
+    }
+
+    /// Verify an earlier verified token again, without checking the
+    /// signature.
+    ///
+    /// The times, audience and revocation of the token are checked,
+    /// as they may have changed since it was verified.
+    #[cfg(feature = "middleware")]
+    pub(crate) fn reverify(
+        &self,
+        context: AuthContext,
+    ) -> Result<AuthContext, Rejected> {
+        let result = self.checked_claims(context);
+        self.count(&result);
+        result
+    }
+
+    fn count(&self, result: &Result<AuthContext, Rejected>) {
+        match *result {
+            Ok(_) => self.metrics.verified(),
+            Err(ref rejected) => self.metrics.rejected(rejected.rejection),
+        }
+    }
+
+    fn check(&self, token: &str) -> Result<AuthContext, Rejected> {
+        if jwe::is_encrypted(token) {
+            return self.check_encrypted(token);
+        }
+        // Token::parse panics if the token has less than two dots.
+        if token.split('.').count() != 3 {
+            info!("Bad jwt token {}: not three parts", fingerprint(token));
+            return Err(Rejection::Malformed.into());
+        }
+        let parsed = match Token::<Header, Claims>::parse(token) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                info!("Bad jwt token {}: {:?}", fingerprint(token), err);
+                return Err(Rejection::Malformed.into());
+            }
+        };
+        let keys = self.keyring.accepted();
+        if !keys.iter().any(|key| parsed.verify(key, Sha256::new())) {
+            info!("Invalid signature on token {}", fingerprint(token));
+            return Err(Rejection::BadSignature.into());
+        }
+        let header = TokenHeader::Signed(parsed.header);
+        self.checked(token, header, parsed.claims)
+    }
+
+    fn check_encrypted(&self, token: &str) -> Result<AuthContext, Rejected> {
+        let mut result = Err(DecryptError::BadKey);
+        for key in self.keyring.accepted() {
+            result = jwe::decrypt(&jwe::encryption_key(&key), token);
+            if result != Err(DecryptError::BadKey) {
+                break;
+            }
+        }
+        // Partners know the shared key, so tokens encrypted with it
+        // are only accepted with a signed token inside.
+        let mut shared = false;
+        if result == Err(DecryptError::BadKey) {
+            if let Some(ref nested) = self.nested {
+                result = nested.decrypt(token);
+                shared = true;
+            }
+        }
+        let decrypted = match result {
+            Ok(decrypted) => decrypted,
+            Err(DecryptError::BadKey) => {
+                info!("Failed to decrypt token {}", fingerprint(token));
+                return Err(Rejection::BadSignature.into());
+            }
+            Err(DecryptError::Malformed) => {
+                info!("Bad encrypted token {}", fingerprint(token));
+                return Err(Rejection::Malformed.into());
+            }
+        };
+        let claims = if decrypted.cty() == Some(NESTED_CTY) {
+            self.verify_inner(token, &decrypted.plaintext)?
+        } else if shared {
+            info!("Unsigned token {} with shared key", fingerprint(token));
+            return Err(Rejection::BadSignature.into());
+        } else {
+            decrypted.plaintext.to_base64(URL_SAFE)
+        };
+        let header = TokenHeader::Encrypted(decrypted.header);
+        match Claims::from_base64(&claims) {
+            Ok(claims) => self.checked(token, header, claims),
+            Err(err) => {
+                info!("Bad claims in token {}: {:?}", fingerprint(token), err);
+                Err(Rejection::Malformed.into())
+            }
+        }
+    }
+
+    /// Check the signed token inside a nested token, and get its
+    /// base64 encoded claims.
+    fn verify_inner(
+        &self,
+        token: &str,
+        inner: &[u8],
+    ) -> Result<String, Rejection> {
+        let result = match self.nested {
+            Some(ref nested) => nested.verify_inner(inner),
+            None => Err(Inner::BadSignature),
+        };
+        result.map_err(|err| {
+            info!("Bad inner token in {}: {:?}", fingerprint(token), err);
+            match err {
+                Inner::Malformed => Rejection::Malformed,
+                Inner::BadSignature => Rejection::BadSignature,
+            }
+        })
+    }
+
+    /// Check the claims of an authentic token.
+    fn checked(
+        &self,
+        token: &str,
+        header: TokenHeader,
+        claims: Claims,
+    ) -> Result<AuthContext, Rejected> {
+        let context = AuthContext {
+            token: token.to_owned(),
+            header: header,
+            registered: claims.reg,
+            custom_claims: claims.private,
+        };
+        debug!("Verified token {}", self.logged(&context));
+        self.checked_claims(context)
+    }
+
+    /// Check the claims of an authentic token, keeping its data if
+    /// it is rejected.
+    fn checked_claims(
+        &self,
+        context: AuthContext,
+    ) -> Result<AuthContext, Rejected> {
+        match self.check_claims(&context) {
+            Ok(()) => Ok(context),
+            Err(rejection) => Err(Rejected {
+                rejection: rejection,
+                context: Some(context),
+            }),
+        }
+    }
+
+    /// Check the times, audience and revocation of a properly signed
+    /// token.
+    fn check_claims(&self, context: &AuthContext) -> Result<(), Rejection> {
+        let reg = &context.registered;
+        let now = ::current_numeric_date();
+        if let Some(nbf) = reg.nbf {
+            if now < nbf {
+                warn!("Got a not-yet valid token: {}", self.logged(context));
+                return Err(Rejection::NotYetValid);
+            }
+        }
+        if let Some(exp) = reg.exp {
+            if now > exp {
+                warn!("Got an expired token: {}", self.logged(context));
+                return Err(Rejection::Expired);
+            }
+        }
+        if self.audience.is_some() && reg.aud != self.audience {
+            warn!(
+                "Got a token for another audience: {}",
+                self.logged(context)
+            );
+            return Err(Rejection::WrongAudience);
+        }
+        if let Some(ref revocation) = self.revocation {
+            if revocation.is_revoked(context) {
+                warn!("Got a revoked token: {}", self.logged(context));
+                return Err(Rejection::Revoked);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a token and its claims as a string to log.
+    fn logged(&self, context: &AuthContext) -> String {
+        format!(
+            "{} ({})",
+            fingerprint(&context.token),
+            self.redaction
+                .all_claims(&context.registered, &context.custom_claims),
+        )
+    }
+
+    /// Create a signed token.
+    pub fn make_token(
+        &self,
+        options: TokenOptions,
+    ) -> Result<String, SessionError> {
+        self.sign(options).map(|(token, _jti)| token)
+    }
+
+    /// Create a signed or encrypted token, and return it with its jti.
+    fn sign(
+        &self,
+        options: TokenOptions,
+    ) -> Result<(String, Option<String>), SessionError> {
+        let header = Header {
+            kid: options.key_id.clone(),
+            ..Default::default()
+        };
+        let now = ::current_numeric_date();
+        let expiration = options.expiration.unwrap_or(self.expiration_time);
+        if expiration.num_seconds() <= 0 {
+            return Err(SessionError::BadExpiration(expiration));
+        }
+        let claims = Claims {
+            reg: Registered {
+                iss: self.issuer.clone(),
+                sub: options.subject,
+                aud: options.audience.or_else(|| self.audience.clone()),
+                exp: Some(now + expiration.num_seconds() as u64),
+                nbf: Some(now),
+                iat: Some(now),
+                jti: new_jti(),
+            },
+            private: options.claims,
+        };
+        let jti = claims.reg.jti.clone();
+        if options.nested {
+            let claims = claims.to_base64().map_err(SessionError::Signing)?;
+            let key_id = options.key_id.as_ref().map(|kid| kid.as_str());
+            let sealed = self.nested
+                .as_ref()
+                .and_then(|nested| nested.seal(&claims, key_id));
+            return match sealed {
+                Some(result) => result
+                    .map(|token| (token, jti))
+                    .map_err(SessionError::Encryption),
+                None => Err(SessionError::NoNestedKey),
+            };
+        }
+        if let Some(encryption) = self.encryption {
+            let plaintext = claims
+                .to_base64()
+                .map_err(SessionError::Signing)?
+                .from_base64()
+                .map_err(|e| SessionError::Signing(e.into()))?;
+            let key = jwe::encryption_key(&self.keyring.current());
+            let key_id = options.key_id.as_ref().map(|kid| kid.as_str());
+            return jwe::encrypt(encryption, &key, None, key_id, &plaintext)
+                .map(|token| (token, jti))
+                .map_err(SessionError::Encryption);
+        }
+        let token = Token::new(header, claims);
+        token
+            .signed(&self.keyring.current(), Sha256::new())
+            .map(|token| (token, jti))
+            .map_err(SessionError::Signing)
+    }
+
+    /// Get the location to put a new token in.
+    ///
+    /// The `found` location is where the token of the request was
+    /// found, if any.
+    pub fn location_for(&self, found: Option<&TokenLocation>) -> TokenLocation {
+        match (self.write_location, found) {
+            (WriteLocation::Mirror, Some(found)) => found.clone(),
+            (_, _) => self.locations[0].clone(),
+        }
+    }
+
+    /// Create a signed token, and what to add to a response to set it
+    /// in the given location.
+    ///
+    /// `old_chunks` is the number of cookie chunks the client has for
+    /// the location, so any stale chunks can be removed.
+    pub fn issue(
+        &self,
+        options: TokenOptions,
+        location: &TokenLocation,
+        old_chunks: usize,
+    ) -> Result<IssuedToken, SessionError> {
+        let expiration = options.expiration.unwrap_or(self.expiration_time);
+        let (token, jti) = self.sign(options)?;
+        self.metrics.issued();
+        let output = set_output(
+            &self.cookie,
+            location,
+            &token,
+            expiration,
+            old_chunks,
+        );
+        Ok(IssuedToken {
+            token: token,
+            expiration: expiration,
+            jti: jti,
+            output: output,
+        })
+    }
+
+    /// Get what to add to a response to clear the token in a location.
+    pub fn clear(
+        &self,
+        location: &TokenLocation,
+        old_chunks: usize,
+    ) -> TokenOutput {
+        self.metrics.cleared();
+        match *location {
+            TokenLocation::Cookie(ref name) => {
+                let mut cookies = vec![self.cookie.removal(name.clone())];
+                cookies.extend(
+                    (0..old_chunks)
+                        .map(|i| self.cookie.removal(chunk_name(name, i))),
+                );
+                TokenOutput::Cookies(cookies)
+            }
+            TokenLocation::AuthorizationHeader => {
+                TokenOutput::Header("Authorization".into(), "Bearer ".into())
+            }
+            TokenLocation::Header(ref name) => {
+                TokenOutput::Header(name.clone(), "".into())
+            }
+            TokenLocation::QueryParam(_) | TokenLocation::ResponseBody => {
+                TokenOutput::Nothing
+            }
+        }
+    }
+
+    /// Get the csrf token for a session with the given `jti`.
+    pub fn csrf_token(&self, jti: &str) -> String {
+        csrf::csrf_token(&self.keyring.current(), jti)
+    }
+
+    /// Check the csrf token submitted with a request.
+    ///
+    /// Requests that don't change state always pass.  Other requests
+    /// pass only if the submitted token matches the session of the
+    /// `context`.  This is only relevant for cookie-based sessions.
+    pub fn check_csrf(
+        &self,
+        method: &str,
+        context: &AuthContext,
+        submitted: Option<&str>,
+    ) -> bool {
+        if !csrf::is_state_changing(method) {
+            return true;
+        }
+        match (context.registered.jti.as_ref(), submitted) {
+            (Some(jti), Some(submitted)) => {
+                self.keyring.accepted().iter().any(|key| {
+                    csrf::is_valid(&csrf::csrf_token(key, jti), submitted)
+                })
+            }
+            (_, _) => false,
+        }
+    }
+
+    /// Get the default expiration time of new tokens.
+    pub fn default_expiration(&self) -> Duration {
+        self.expiration_time
+    }
+}
+
+/// Get an OAuth2 style json body for a token.
+///
+/// The body is on the form `{"access_token": "...", "token_type":
+/// "Bearer", "expires_in": 86400}`.
+pub fn token_response(token: &str, expiration: Duration) -> Json {
+    let mut body = BTreeMap::new();
+    body.insert("access_token".to_owned(), Json::String(token.to_owned()));
+    body.insert("token_type".to_owned(), Json::String("Bearer".into()));
+    body.insert(
+        "expires_in".to_owned(),
+        Json::I64(expiration.num_seconds()),
+    );
+    Json::Object(body)
+}
+
+/// Max length of a cookie value.
+///
+/// Browsers commonly limit a cookie, including its name and
+/// attributes, to 4096 bytes.  Tokens longer than this are split
+/// over several cookies named `name.0`, `name.1`, and so on.
+const MAX_COOKIE_VALUE: usize = 3800;
+
+fn chunk_name(name: &str, i: usize) -> String {
+    format!("{}.{}", name, i)
+}
+
+/// Get the values of the `name.0`, `name.1`, ... cookies, in order.
+fn cookie_chunks<S: TokenSource + ?Sized>(
+    source: &S,
+    name: &str,
+) -> Vec<String> {
+    (0..)
+        .map(|i| source.cookie(&chunk_name(name, i)))
+        .take_while(Option::is_some)
+        .map(Option::unwrap)
+        .collect()
+}
+
+/// Get the number of jwt cookie chunks with the given name in a request.
+pub fn cookie_chunk_count<S: TokenSource + ?Sized>(
+    source: &S,
+    name: &str,
+) -> usize {
+    cookie_chunks(source, name).len()
+}
+
+/// Get the value of a cookie, reassembling it from chunks if needed.
+fn get_cookie<S: TokenSource + ?Sized>(
+    source: &S,
+    name: &str,
+) -> Option<String> {
+    if let Some(value) = source.cookie(name) {
+        return Some(value);
+    }
+    let chunks = cookie_chunks(source, name);
+    if chunks.is_empty() {
+        None
+    } else {
+        Some(chunks.concat())
+    }
+}
+
+/// Get what to add to a response to set the token in the specified
+/// location, valid for the expiration time from the current time.
+fn set_output(
+    attributes: &CookieAttributes,
+    location: &TokenLocation,
+    token: &str,
+    expiration: Duration,
+    old_chunks: usize,
+) -> TokenOutput {
+    match *location {
+        TokenLocation::Cookie(ref name) => {
+            let mut cookies = Vec::new();
+            if token.len() <= MAX_COOKIE_VALUE {
+                let cookie = attributes.cookie(
+                    name.clone(),
+                    token.to_owned(),
+                    expiration,
+                );
+                debug!(
+                    "Setting new cookie {} with token {}",
+                    name,
+                    fingerprint(token)
+                );
+                cookies.push(cookie.to_string());
+                cookies.extend(
+                    (0..old_chunks)
+                        .map(|i| attributes.removal(chunk_name(name, i))),
+                );
+            } else {
+                // The token is base64 and dots, so splitting on any
+                // byte boundary is fine.
+                let chunks = token.as_bytes().chunks(MAX_COOKIE_VALUE);
+                for (i, chunk) in chunks.enumerate() {
+                    let value = String::from_utf8_lossy(chunk).into_owned();
+                    let cookie = attributes.cookie(
+                        chunk_name(name, i),
+                        value,
+                        expiration,
+                    );
+                    debug!(
+                        "Setting new cookie {} with token {}",
+                        cookie.name(),
+                        fingerprint(token)
+                    );
+                    cookies.push(cookie.to_string());
+                }
+                let new_chunks = cookies.len();
+                cookies.push(attributes.removal(name.clone()));
+                cookies.extend(
+                    (new_chunks..max(old_chunks, new_chunks))
+                        .map(|i| attributes.removal(chunk_name(name, i))),
+                );
+            }
+            TokenOutput::Cookies(cookies)
+        }
+        TokenLocation::AuthorizationHeader => {
+            debug!("Setting new auth header with token {}", fingerprint(token));
+            TokenOutput::Header(
+                "Authorization".into(),
+                format!("Bearer {}", token),
+            )
+        }
+        TokenLocation::Header(ref name) => {
+            debug!(
+                "Setting new {} header with token {}",
+                name,
+                fingerprint(token)
+            );
+            TokenOutput::Header(name.clone(), token.to_owned())
+        }
+        TokenLocation::QueryParam(ref name) => {
+            debug!(
+                "New token for query param {} is {}",
+                name,
+                fingerprint(token)
+            );
+            TokenOutput::Nothing
+        }
+        TokenLocation::ResponseBody => {
+            debug!("New token for response body is {}", fingerprint(token));
+            TokenOutput::Nothing
+        }
+    }
+}
+
+/// Create a random value for the jti (jwt id) claim.
+fn new_jti() -> Option<String> {
+    match OsRng::new() {
+        Ok(mut rng) => {
+            let mut id = [0u8; 16];
+            rng.fill_bytes(&mut id);
+            Some(id.to_hex())
+        }
+        Err(err) => {
+            warn!("Failed to get random jti: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &'static str = "A secret key for tests, at least 32 bytes";
+
+    /// Split `Set-Cookie` values into set cookies and removed names.
+    fn set_cookies(
+        output: TokenOutput,
+    ) -> (Vec<(String, String)>, Vec<String>) {
+        let cookies = match output {
+            TokenOutput::Cookies(cookies) => cookies,
+            other => panic!("Expected cookies, got {:?}", other),
+        };
+        let (mut set, mut removed) = (Vec::new(), Vec::new());
+        for cookie in cookies {
+            let cookie = Cookie::parse(cookie).unwrap();
+            if cookie.value().is_empty() {
+                removed.push(cookie.name().to_owned());
+            } else {
+                set.push((cookie.name().to_owned(), cookie.value().to_owned()));
+            }
+        }
+        (set, removed)
+    }
+
+    fn cookie_header(cookies: &[(String, String)]) -> String {
+        cookies
+            .iter()
+            .map(|&(ref name, ref value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    #[test]
+    fn large_token_round_trip_in_cookie_chunks() {
+        let core = SessionCore::new(KEY);
+        let location = TokenLocation::Cookie("jwt".to_owned());
+        let mut options = TokenOptions::default();
+        options
+            .claims
+            .insert("data".to_owned(), Json::String("x".repeat(5000)));
+        // The client has four old chunks, the new token needs two.
+        let issued = core.issue(options, &location, 4).unwrap();
+        let (set, removed) = set_cookies(issued.output);
+        let names = set.iter().map(|c| c.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["jwt.0", "jwt.1"]);
+        assert_eq!(removed, ["jwt", "jwt.2", "jwt.3"]);
+
+        let mut request =
+            RequestParts::new().header("Cookie", &cookie_header(&set));
+        assert_eq!(cookie_chunk_count(&request, "jwt"), 2);
+        let found = core.find_token(&mut request).unwrap();
+        assert_eq!(found.token, issued.token);
+        let context = core.verify(&found.token).unwrap();
+        assert_eq!(
+            context.custom_claims.get("data"),
+            Some(&Json::String("x".repeat(5000)))
+        );
+    }
+
+    #[test]
+    fn small_token_removes_old_chunks() {
+        let core = SessionCore::new(KEY);
+        let location = TokenLocation::Cookie("jwt".to_owned());
+        let issued = core.issue(TokenOptions::default(), &location, 2);
+        let (set, removed) = set_cookies(issued.unwrap().output);
+        assert_eq!(set.len(), 1);
+        assert_eq!(set[0].0, "jwt");
+        assert_eq!(removed, ["jwt.0", "jwt.1"]);
+    }
+
+    #[test]
+    fn csrf_token_must_match_session() {
+        let core = SessionCore::new(KEY);
+        let location = TokenLocation::Cookie("jwt".to_owned());
+        let issued = core.issue(TokenOptions::default(), &location, 0);
+        let issued = issued.unwrap();
+        let context = core.verify(&issued.token).unwrap();
+        let expected = core.csrf_token(issued.jti.as_ref().unwrap());
+        assert!(!core.check_csrf("POST", &context, None));
+        assert!(!core.check_csrf("POST", &context, Some("wrong")));
+        assert!(!core.check_csrf("DELETE", &context, Some("")));
+        assert!(core.check_csrf("POST", &context, Some(&expected)));
+        assert!(core.check_csrf("GET", &context, None));
+        assert!(core.check_csrf("HEAD", &context, Some("wrong")));
+
+        let other = core.issue(TokenOptions::default(), &location, 0);
+        let other = core.verify(&other.unwrap().token).unwrap();
+        assert!(!core.check_csrf("POST", &other, Some(&expected)));
+    }
+
+    #[test]
+    #[cfg(feature = "middleware")]
+    fn csrf_applies_only_to_cookie_sessions() {
+        let core = SessionCore::new(KEY)
+            .using(TokenLocation::AuthorizationHeader)
+            .also_using(TokenLocation::Cookie("jwt".to_owned()));
+        let token = core.make_token(TokenOptions::default()).unwrap();
+
+        let mut request = RequestParts::new()
+            .header("Authorization", &format!("Bearer {}", token));
+        let found = core.find_token(&mut request).unwrap();
+        assert!(!csrf::applies_to(&found.location));
+
+        let mut request =
+            RequestParts::new().header("Cookie", &format!("jwt={}", token));
+        let found = core.find_token(&mut request).unwrap();
+        assert!(csrf::applies_to(&found.location));
+    }
+
+    #[test]
+    fn rejected_authentic_token_keeps_claims() {
+        let core = SessionCore::new(KEY).audience("app");
+        let options = TokenOptions {
+            subject: Some("carl".to_owned()),
+            audience: Some("other".to_owned()),
+            ..Default::default()
+        };
+        let token = core.make_token(options).unwrap();
+        let rejected = core.verify_detailed(&token).unwrap_err();
+        assert_eq!(rejected.rejection, Rejection::WrongAudience);
+        let context = rejected.context.unwrap();
+        assert_eq!(context.registered.sub, Some("carl".to_owned()));
+        assert!(context.registered.jti.is_some());
+
+        let other = SessionCore::new("Another secret key, also 32 bytes long");
+        let rejected = other.verify_detailed(&token).unwrap_err();
+        assert_eq!(rejected.rejection, Rejection::BadSignature);
+        assert!(rejected.context.is_none());
+    }
+
+    #[test]
+    fn encrypted_token_round_trip() {
+        let location = TokenLocation::Cookie("jwt".to_owned());
+        for &encryption in &[Encryption::Direct, Encryption::KeyWrap] {
+            let core = SessionCore::new(KEY).encrypt_tokens(encryption);
+            let mut options = TokenOptions {
+                subject: Some("carl".to_owned()),
+                key_id: Some("k1".to_owned()),
+                ..Default::default()
+            };
+            options.claims.insert("admin".to_owned(), Json::Boolean(true));
+            let issued = core.issue(options, &location, 0).unwrap();
+            assert!(jwe::is_encrypted(&issued.token));
+            assert!(!issued.token.contains(".eyJ"));
+
+            let context = core.verify(&issued.token).unwrap();
+            assert_eq!(context.registered.sub, Some("carl".to_owned()));
+            assert_eq!(context.registered.jti, issued.jti);
+            assert_eq!(
+                context.custom_claims.get("admin"),
+                Some(&Json::Boolean(true))
+            );
+            assert_eq!(context.header.key_id(), Some("k1"));
+            match context.header {
+                TokenHeader::Encrypted(ref header) => {
+                    let alg = header.get("alg").and_then(Json::as_string);
+                    assert_eq!(alg, Some(encryption.to_string().as_str()));
+                }
+                TokenHeader::Signed(_) => panic!("Expected a jwe header"),
+            }
+
+            let other =
+                SessionCore::new("Another secret key, also 32 bytes long");
+            assert_eq!(
+                other.verify(&issued.token).unwrap_err(),
+                Rejection::BadSignature
+            );
+        }
+    }
+
+    #[test]
+    fn nested_tokens_need_an_accepted_inner_signature() {
+        let shared_key = [1; 32];
+        let nested = NestedTokens::new(Encryption::KeyWrap, &shared_key)
+            .unwrap()
+            .sign_with(&[2; 32])
+            .unwrap();
+        let core = SessionCore::new(KEY).nested_tokens(nested);
+        let options = TokenOptions {
+            subject: Some("carl".to_owned()),
+            key_id: Some("k1".to_owned()),
+            nested: true,
+            ..Default::default()
+        };
+        let token = core.make_token(options).unwrap();
+        let context = core.verify(&token).unwrap();
+        assert_eq!(context.registered.sub, Some("carl".to_owned()));
+        assert_eq!(context.header.key_id(), Some("k1"));
+
+        // Encrypted with the shared key, but not signed.
+        let claims = br#"{"sub":"mallory"}"#;
+        for &cty in &[None, Some("json")] {
+            let unsigned =
+                jwe::encrypt(Encryption::Direct, &shared_key, cty, None, claims)
+                    .unwrap();
+            assert_eq!(
+                core.verify(&unsigned).unwrap_err(),
+                Rejection::BadSignature
+            );
+        }
+
+        // Signed, but with a key that is not accepted.
+        let stranger = NestedTokens::new(Encryption::KeyWrap, &shared_key)
+            .unwrap()
+            .sign_with(&[3; 32])
+            .unwrap();
+        let claims = claims.to_base64(URL_SAFE);
+        let forged = stranger.seal(&claims, None).unwrap().unwrap();
+        assert_eq!(core.verify(&forged).unwrap_err(), Rejection::BadSignature);
+    }
+
+    #[test]
+    fn locations_are_searched_in_order() {
+        let core = SessionCore::new(KEY)
+            .using(TokenLocation::Header("X-Auth-Token".to_owned()))
+            .also_using(TokenLocation::Cookie("jwt".to_owned()))
+            .also_using(TokenLocation::AuthorizationHeader);
+        let find = |request: &mut RequestParts| {
+            core.find_token(request).map(|found| {
+                (found.token, found.location)
+            })
+        };
+
+        let mut request = RequestParts::new()
+            .header("Authorization", "Bearer c")
+            .header("Cookie", "jwt=b")
+            .header("X-Auth-Token", "a");
+        assert_eq!(
+            find(&mut request),
+            Some(("a".into(), TokenLocation::Header("X-Auth-Token".into())))
+        );
+        let mut request = RequestParts::new()
+            .header("Authorization", "Bearer c")
+            .header("Cookie", "jwt=b");
+        assert_eq!(
+            find(&mut request),
+            Some(("b".into(), TokenLocation::Cookie("jwt".into())))
+        );
+        let mut request =
+            RequestParts::new().header("Authorization", "Bearer c");
+        assert_eq!(
+            find(&mut request),
+            Some(("c".into(), TokenLocation::AuthorizationHeader))
+        );
+        let mut request =
+            RequestParts::new().header("Authorization", "Basic Y2FybA==");
+        assert_eq!(find(&mut request), None);
+    }
+
+    #[test]
+    fn mirror_writes_where_the_token_was_found() {
+        let cookie = TokenLocation::Cookie("jwt".to_owned());
+        let bearer = TokenLocation::AuthorizationHeader;
+        let core = SessionCore::new(KEY)
+            .using(cookie.clone())
+            .also_using(bearer.clone());
+        assert_eq!(core.location_for(Some(&bearer)), cookie);
+        assert_eq!(core.location_for(None), cookie);
+
+        let core = core.write_location(WriteLocation::Mirror);
+        let mut request =
+            RequestParts::new().header("Authorization", "Bearer c");
+        let found = core.find_token(&mut request).unwrap();
+        assert_eq!(core.location_for(Some(&found.location)), bearer);
+        assert_eq!(core.location_for(None), cookie);
+    }
+
+    #[test]
+    fn query_values_are_percent_decoded() {
+        let mut request = RequestParts::new().query(
+            "plus=a%2Bb&space=a+b&trailing=50%&bad=%zz&utf8=%C3%A5%E2%82%AC",
+        );
+        let mut get = |name| request.query_param(name);
+        assert_eq!(get("plus"), Some("a+b".to_owned()));
+        assert_eq!(get("space"), Some("a b".to_owned()));
+        assert_eq!(get("trailing"), Some("50%".to_owned()));
+        assert_eq!(get("bad"), Some("%zz".to_owned()));
+        assert_eq!(get("utf8"), Some("å€".to_owned()));
+        assert_eq!(get("missing"), None);
+    }
+
+    #[test]
+    fn tokens_in_custom_header_and_query_param() {
+        let core = SessionCore::new(KEY)
+            .using(TokenLocation::Header("X-Auth-Token".to_owned()))
+            .also_using(TokenLocation::QueryParam("access_token".to_owned()));
+        let mut request = RequestParts::new().header("x-auth-token", "a");
+        let found = core.find_token(&mut request).unwrap();
+        assert_eq!(found.token, "a");
+
+        let mut request =
+            RequestParts::new().query("page=2&access_token=a.b%2Bc");
+        let found = core.find_token(&mut request).unwrap();
+        assert_eq!(found.token, "a.b+c");
+        assert_eq!(
+            found.location,
+            TokenLocation::QueryParam("access_token".to_owned())
+        );
+    }
+
+    #[test]
+    fn response_body_tokens() {
+        let core = SessionCore::new(KEY)
+            .using(TokenLocation::ResponseBody)
+            .expiration_time(Duration::hours(1));
+        let location = TokenLocation::ResponseBody;
+        let issued = core.issue(TokenOptions::default(), &location, 0);
+        let issued = issued.unwrap();
+        assert_eq!(issued.output, TokenOutput::Nothing);
+        assert_eq!(issued.expiration, Duration::hours(1));
+
+        let body = token_response(&issued.token, issued.expiration);
+        let field = |name| body.find(name).cloned();
+        let token = Json::String(issued.token.clone());
+        assert_eq!(field("access_token"), Some(token));
+        assert_eq!(field("token_type"), Some(Json::String("Bearer".into())));
+        assert_eq!(field("expires_in"), Some(Json::I64(3600)));
+
+        let mut request = RequestParts::new()
+            .header("Authorization", &format!("Bearer {}", issued.token));
+        let found = core.find_token(&mut request).unwrap();
+        assert_eq!(found.location, TokenLocation::ResponseBody);
+        assert!(core.verify(&found.token).is_ok());
+    }
+
+
+    #[test]
+    fn expiration_must_be_positive() {
+        let core = SessionCore::new(KEY);
+        for &seconds in &[0, -60, i64::min_value() / 1000] {
+            let options = TokenOptions {
+                expiration: Some(Duration::seconds(seconds)),
+                ..Default::default()
+            };
+            match core.make_token(options) {
+                Err(SessionError::BadExpiration(_)) => (),
+                other => panic!("Expected bad expiration, got {:?}", other),
+            }
+        }
+        let core = core.expiration_time(Duration::seconds(-1));
+        match core.make_token(TokenOptions::default()) {
+            Err(SessionError::BadExpiration(_)) => (),
+            other => panic!("Expected bad expiration, got {:?}", other),
+        }
+
+        let core = core.expiration_time(Duration::hours(1));
+        let token = core.make_token(TokenOptions::default()).unwrap();
+        let reg = core.verify(&token).unwrap().registered;
+        let iat = reg.iat.expect("iat");
+        assert_eq!(reg.nbf, Some(iat));
+        assert_eq!(reg.exp, Some(iat + 3600));
+    }
+
+
+    #[test]
+    fn tokens_need_three_parts() {
+        let core = SessionCore::new(KEY);
+        let token = core.make_token(TokenOptions::default()).unwrap();
+        let (header, rest) = token.split_at(token.find('.').unwrap());
+        let four_parts = format!("{}.x", token);
+        let bad_tokens =
+            ["", "eyJhbGciOiJIUzI1NiJ9", header, &rest[1..], &four_parts];
+        for bad in &bad_tokens {
+            assert_eq!(core.verify(bad).unwrap_err(), Rejection::Malformed);
+        }
+        assert!(core.verify(&token).is_ok());
+    }
+
+
+    #[test]
+    fn nested_tokens_need_a_nested_key() {
+        let core = SessionCore::new(KEY);
+        let location = TokenLocation::Cookie("jwt".to_owned());
+        let options = || TokenOptions {
+            subject: Some("carl".to_owned()),
+            nested: true,
+            ..Default::default()
+        };
+        match core.make_token(options()) {
+            Err(SessionError::NoNestedKey) => (),
+            other => panic!("Expected no nested key, got {:?}", other),
+        }
+        match core.issue(options(), &location, 0) {
+            Err(SessionError::NoNestedKey) => (),
+            other => panic!("Expected no nested key, got {:?}", other),
+        }
+        assert_eq!(core.metrics().snapshot().issued, 0);
+    }
+
+
+    #[test]
+    fn debug_shows_no_tokens() {
+        let core = SessionCore::new(KEY);
+        let options = TokenOptions {
+            subject: Some("carl".to_owned()),
+            ..Default::default()
+        };
+        let location = TokenLocation::Cookie("jwt".to_owned());
+        let issued = core.issue(options, &location, 0).unwrap();
+        let token = issued.token.clone();
+        let mut request = RequestParts::new()
+            .header("Cookie", &format!("jwt={}", token))
+            .header("Authorization", &format!("Bearer {}", token));
+        let found = core.find_token(&mut request).unwrap();
+        let context = core.verify(&token).unwrap();
+        let header = TokenOutput::Header("Authorization".into(), token.clone());
+
+        for debug in &[
+            format!("{:?}", issued),
+            format!("{:?}", found),
+            format!("{:?}", context),
+            format!("{:?}", header),
+        ] {
+            assert!(!debug.contains(&token), "{} has the token", debug);
+            assert!(debug.contains(&fingerprint(&token)), "{}", debug);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Loading an application user object for the subject of a token.
+
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use typemap::{Key, TypeMap};
+
+/// A hook for loading the user for a valid token.
+///
+/// Implement this for e.g. a database connection pool, and register
+/// it with `SessionMiddleware::user_loader()`.  The loader is called
+/// once per request, after the token is verified, and the loaded user
+/// is available to handlers through the `current_user()` request
+/// method.
+pub trait UserLoader: Send + Sync + 'static {
+    /// The type of user object to load.
+    type User: 'static;
+
+    /// Load the user for the `sub` (subject) claim of a valid token.
+    ///
+    /// The custom claims of the token are also provided, in case
+    /// they are needed to find the user.  Return None if there is no
+    /// such user.
+    fn load(
+        &self,
+        subject: &str,
+        claims: &BTreeMap<String, Json>,
+    ) -> Option<Self::User>;
+}
+
+/// A `UserLoader` with the user type erased, so it can be stored in
+/// the (non-generic) `SessionMiddleware`.
+pub trait LoadUser: Send + Sync {
+    fn load_into(
+        &self,
+        subject: &str,
+        claims: &BTreeMap<String, Json>,
+        extensions: &mut TypeMap,
+    );
+}
+
+impl<L: UserLoader> LoadUser for L {
+    fn load_into(
+        &self,
+        subject: &str,
+        claims: &BTreeMap<String, Json>,
+        extensions: &mut TypeMap,
+    ) {
+        match self.load(subject, claims) {
+            Some(user) => {
+                extensions.insert::<CurrentUser<L::User>>(user);
+            }
+            None => debug!("No user loaded for {:?}", subject),
+        }
+    }
+}
+
+/// Typemap key for the loaded user.
+pub struct CurrentUser<U> {
+    user: PhantomData<U>,
+}
+
+impl<U: 'static> Key for CurrentUser<U> {
+    type Value = U;
+}